@@ -0,0 +1,304 @@
+use crate::events::warn;
+use crate::nuspec::Nuspec;
+
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// How long cached feed metadata is used before being fetched again
+const INDEX_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// What a feed sent along with a package to identify the copy it served, so it can later
+/// be asked whether that's still what it serves
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Validators {
+    /// The url the package was downloaded from, as other urls don't know these validators
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// A directory of previously downloaded nupkgs shared between projects
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+/// A package in the cache, as listed by `Cache::entries`
+#[derive(Debug)]
+pub struct CacheEntry {
+    /// The lowercased id and version
+    pub name: String,
+    pub version: String,
+    pub size: u64,
+    /// When the package was last installed from the cache or put into it
+    pub accessed: SystemTime,
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The cache directory used when none is given
+    ///
+    /// This is `%LOCALAPPDATA%\cargo-nuget\cache` on Windows and `~/.cache/cargo-nuget` elsewhere.
+    pub fn default_dir() -> Option<PathBuf> {
+        if let Ok(local) = std::env::var("LOCALAPPDATA") {
+            return Some(Path::new(&local).join("cargo-nuget").join("cache"));
+        }
+        if let Ok(cache) = std::env::var("XDG_CACHE_HOME") {
+            return Some(Path::new(&cache).join("cargo-nuget"));
+        }
+        let home = std::env::var("HOME").ok()?;
+        Some(Path::new(&home).join(".cache").join("cargo-nuget"))
+    }
+
+    /// Where the given package version is stored in the cache
+    ///
+    /// Ids and versions are case insensitive in NuGet so they're lowercased.
+    pub fn path(&self, name: &str, version: &str) -> PathBuf {
+        let name = name.to_lowercase();
+        let version = version.to_lowercase();
+        self.dir
+            .join(&name)
+            .join(format!("{}.{}.nupkg", name, version))
+    }
+
+    /// Where the validators of a cached package are stored, next to the package
+    fn validators_path(&self, name: &str, version: &str) -> PathBuf {
+        self.path(name, version).with_extension("validators.json")
+    }
+
+    /// Reads the given package version from the cache, if it's there
+    ///
+    /// A cached package that isn't a valid nupkg of that version (e.g. because it was
+    /// truncated) is removed so it's downloaded again instead of breaking every install.
+    pub fn get(&self, name: &str, version: &str) -> Option<Vec<u8>> {
+        let path = self.path(name, version);
+        let bytes = std::fs::read(&path).ok()?;
+        match Nuspec::from_package(name, &bytes) {
+            Ok(nuspec) if nuspec.matches(name, version) => {
+                // The modification time is the last access, which `cache gc` evicts by.
+                // Access times themselves are often not recorded.
+                let _ = File::options()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|f| f.set_modified(SystemTime::now()));
+                Some(bytes)
+            }
+            _ => {
+                warn(format!(
+                    "removing corrupt cached package {} {} at {}",
+                    name,
+                    version,
+                    path.display()
+                ));
+                if let Err(e) = std::fs::remove_file(&path) {
+                    warn(format!("Could not remove {}: {}", path.display(), e));
+                }
+                let _ = std::fs::remove_file(self.validators_path(name, version));
+                None
+            }
+        }
+    }
+
+    /// Reads the validators recorded when the given package version was cached
+    pub fn get_validators(&self, name: &str, version: &str) -> Option<Validators> {
+        let bytes = std::fs::read(self.validators_path(name, version)).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+
+    /// Caches a package along with the validators it was served with, if there were any
+    pub fn put(
+        &self,
+        name: &str,
+        version: &str,
+        bytes: &[u8],
+        validators: Option<&Validators>,
+    ) -> std::io::Result<()> {
+        // Other installs may be caching the same package at the same time
+        let _lock = lock(&self.path(name, version))?;
+        let validators_path = self.validators_path(name, version);
+        // Validators of a previous copy must not be used for this one
+        match std::fs::remove_file(&validators_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        write(&self.path(name, version), bytes)?;
+        match validators.filter(|v| v.etag.is_some() || v.last_modified.is_some()) {
+            // Serializing validators can't fail
+            Some(v) => write(&validators_path, &serde_json::to_vec(v).unwrap()),
+            None => Ok(()),
+        }
+    }
+
+    /// Every cached package, sorted by id and version
+    pub fn entries(&self) -> std::io::Result<Vec<CacheEntry>> {
+        let mut entries = Vec::new();
+        let dirs = match std::fs::read_dir(&self.dir) {
+            Ok(dirs) => dirs,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(entries),
+            Err(e) => return Err(e),
+        };
+        for dir in dirs {
+            let dir = dir?;
+            let name = dir.file_name().to_string_lossy().into_owned();
+            if !dir.file_type()?.is_dir() || name == "index" {
+                continue;
+            }
+            for file in std::fs::read_dir(dir.path())? {
+                let file = file?;
+                let file_name = file.file_name().to_string_lossy().into_owned();
+                let version = match file_name
+                    .strip_prefix(&format!("{}.", name))
+                    .and_then(|f| f.strip_suffix(".nupkg"))
+                {
+                    Some(version) => version.to_owned(),
+                    None => continue,
+                };
+                let metadata = file.metadata()?;
+                entries.push(CacheEntry {
+                    name: name.clone(),
+                    version,
+                    size: metadata.len(),
+                    accessed: metadata.modified()?,
+                });
+            }
+        }
+        entries.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+        Ok(entries)
+    }
+
+    /// Removes a cached package along with its validators
+    pub fn remove(&self, name: &str, version: &str) -> std::io::Result<()> {
+        let path = self.path(name, version);
+        let _lock = lock(&path)?;
+        std::fs::remove_file(&path)?;
+        let _ = std::fs::remove_file(self.validators_path(name, version));
+        Ok(())
+    }
+
+    /// Removes everything in the cache
+    pub fn clean(&self) -> std::io::Result<()> {
+        match std::fs::remove_dir_all(&self.dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Where the response of the feed metadata document at `url` is stored
+    fn index_path(&self, url: &str) -> PathBuf {
+        let hash = format!("{:x}", Sha256::digest(url.as_bytes()));
+        self.dir.join("index").join(format!("{}.json", hash))
+    }
+
+    /// Reads the cached response for the feed metadata document at `url`, unless it's
+    /// older than `INDEX_TTL`
+    pub fn get_index(&self, url: &str) -> Option<Vec<u8>> {
+        let path = self.index_path(url);
+        let age = std::fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|m| SystemTime::now().duration_since(m).ok())?;
+        if age > INDEX_TTL {
+            return None;
+        }
+        std::fs::read(path).ok()
+    }
+
+    pub fn put_index(&self, url: &str, bytes: &[u8]) -> std::io::Result<()> {
+        let path = self.index_path(url);
+        let _lock = lock(&path)?;
+        write(&path, bytes)
+    }
+}
+
+/// Takes an advisory lock on the cache entry at `path`, which is held until the returned
+/// file is dropped
+///
+/// Several installs running at once, e.g. build scripts of crates built in parallel, share
+/// the cache and must not write the same entry at the same time.
+fn lock(path: &Path) -> std::io::Result<File> {
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let file = File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path.with_extension("lock"))?;
+    file.lock_exclusive()?;
+    Ok(file)
+}
+
+/// Writes a file into the cache
+///
+/// The contents go into a temporary file that's renamed into place so an interrupted
+/// write never leaves a truncated entry behind, and readers only ever see a complete one.
+/// The temporary file is named after the process and numbered within it so installs and
+/// downloads running at once can't write into each other's.
+fn write(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let tmp = path.with_extension(format!(
+        "{}.{}.tmp",
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+    if let Err(e) = std::fs::write(&tmp, bytes).and_then(|_| std::fs::rename(&tmp, path)) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    /// A nupkg with nothing but a nuspec
+    fn package(name: &str, version: &str) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        zip.start_file(format!("{}.nuspec", name), Default::default())
+            .unwrap();
+        write!(
+            zip,
+            "<package><metadata><id>{}</id><version>{}</version></metadata></package>",
+            name, version
+        )
+        .unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn concurrent_puts_leave_a_valid_package() {
+        let dir = std::env::temp_dir().join(format!("cargo-nuget-cache-{}", std::process::id()));
+        let cache = Cache::new(dir.clone());
+        let bytes = package("Test", "1.0.0");
+        let writers: Vec<_> = (0..2)
+            .map(|_| {
+                let (cache, bytes) = (cache.clone(), bytes.clone());
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        cache.put("Test", "1.0.0", &bytes, None).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(cache.get("Test", "1.0.0"), Some(bytes));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::Mutex;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Limits how many packages are downloaded and extracted at the same time
+///
+/// An adaptive limit is lowered and raised like TCP's congestion window: it's halved when a
+/// source answers that it's overloaded and grows by one once as many packages as the limit
+/// succeeded in a row, always staying within its bounds.
+#[derive(Debug)]
+pub struct Concurrency {
+    semaphore: Semaphore,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    limit: usize,
+    min: usize,
+    max: usize,
+    /// Permits to take out of circulation as they're released, after the limit was lowered
+    /// below the number of packages being worked on
+    excess: usize,
+    /// The packages that succeeded since the limit last changed
+    successes: usize,
+}
+
+impl Concurrency {
+    /// A limit that never changes
+    pub fn fixed(jobs: usize) -> Self {
+        Self::adaptive(jobs, jobs, jobs)
+    }
+
+    /// A limit starting at `jobs` that moves between `min` and `max`
+    pub fn adaptive(jobs: usize, min: usize, max: usize) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+        let limit = jobs.max(min).min(max);
+        Self {
+            semaphore: Semaphore::new(limit),
+            state: Mutex::new(State {
+                limit,
+                min,
+                max,
+                excess: 0,
+                successes: 0,
+            }),
+        }
+    }
+
+    /// Waits until another package may be worked on, until the returned slot is dropped
+    pub async fn acquire(&self) -> Slot<'_> {
+        Slot {
+            permit: Some(self.semaphore.acquire().await),
+            concurrency: self,
+        }
+    }
+
+    /// Halves the limit after a source answered with `429 Too Many Requests` or
+    /// `503 Service Unavailable`
+    pub fn back_off(&self) {
+        let mut state = self.state.lock().unwrap();
+        // The packages in flight when the limit was lowered likely got the same answer,
+        // which shouldn't lower it again before it took effect
+        if state.excess > 0 || state.limit == state.min {
+            return;
+        }
+        let limit = (state.limit / 2).max(state.min);
+        state.excess += state.limit - limit;
+        state.limit = limit;
+        state.successes = 0;
+        eprintln!(
+            "A source is overloaded, working on at most {} package(s) at a time",
+            limit
+        );
+    }
+
+    /// Raises the limit by one once as many packages as the limit succeeded
+    pub fn succeeded(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.successes += 1;
+        if state.successes < state.limit || state.limit == state.max {
+            return;
+        }
+        state.limit += 1;
+        state.successes = 0;
+        if state.excess > 0 {
+            state.excess -= 1;
+        } else {
+            self.semaphore.add_permits(1);
+        }
+    }
+}
+
+/// The permission to work on a package, see `Concurrency::acquire`
+#[derive(Debug)]
+pub struct Slot<'a> {
+    permit: Option<SemaphorePermit<'a>>,
+    concurrency: &'a Concurrency,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut state = self.concurrency.state.lock().unwrap();
+        if state.excess > 0 {
+            state.excess -= 1;
+            if let Some(permit) = self.permit.take() {
+                permit.forget();
+            }
+        }
+    }
+}
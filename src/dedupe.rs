@@ -0,0 +1,118 @@
+use crate::store::Store;
+use crate::{create_dir, Error, Winmd};
+
+use sha2::{Digest, Sha256};
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// What to do with a winmd identical to one another package already installed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dedupe {
+    /// Install a copy of it like any other file
+    Copy,
+    /// Leave it out
+    SkipDuplicates,
+    /// Hard link it to the copy that's already installed
+    Hardlink,
+}
+
+impl Dedupe {
+    pub const VARIANTS: &'static [&'static str] = &["copy", "skip-duplicates", "hardlink"];
+}
+
+impl FromStr for Dedupe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "copy" => Ok(Dedupe::Copy),
+            "skip-duplicates" => Ok(Dedupe::SkipDuplicates),
+            "hardlink" => Ok(Dedupe::Hardlink),
+            _ => Err(format!("unknown dedupe strategy `{}`", s)),
+        }
+    }
+}
+
+/// Writes the winmds of every package of an install, applying `--dedupe` to those another
+/// package already installed with the same contents
+///
+/// Windows SDK contract packages often ship dozens of identical winmds.
+#[derive(Debug)]
+pub struct Deduper {
+    strategy: Dedupe,
+    /// Where the first winmd with a given SHA-256 hash was installed
+    installed: HashMap<String, PathBuf>,
+    duplicates: usize,
+    /// The bytes not written because of duplicates
+    saved: u64,
+}
+
+impl Deduper {
+    pub fn new(strategy: Dedupe) -> Self {
+        Self {
+            strategy,
+            installed: HashMap::new(),
+            duplicates: 0,
+            saved: 0,
+        }
+    }
+
+    /// Writes `winmd` into `dir`, the staging directory of a package that's installed into
+    /// `package_dir`
+    pub fn write(
+        &mut self,
+        winmd: &Winmd,
+        dir: &Path,
+        package_dir: &Path,
+        store: Option<&Store>,
+    ) -> Result<(), Error> {
+        let hash = format!("{:x}", Sha256::digest(&winmd.contents));
+        let first = match self.installed.get(&hash) {
+            // Winmds of several frameworks of the same package aren't installed yet
+            Some(first) if !first.starts_with(package_dir) => first.clone(),
+            Some(_) => return winmd.write(dir, store),
+            None => {
+                winmd.write(dir, store)?;
+                self.installed.insert(hash, package_dir.join(&winmd.name));
+                return Ok(());
+            }
+        };
+        self.duplicates += 1;
+        match self.strategy {
+            Dedupe::Copy => winmd.write(dir, store),
+            Dedupe::SkipDuplicates => {
+                self.saved += winmd.contents.len() as u64;
+                Ok(())
+            }
+            Dedupe::Hardlink => {
+                let path = dir.join(&winmd.name);
+                create_dir(path.parent().unwrap())?;
+                // Hard links can't cross filesystems, in which case it's a copy after all
+                if std::fs::hard_link(&first, &path).is_err() {
+                    return winmd.write(dir, store);
+                }
+                self.saved += winmd.contents.len() as u64;
+                Ok(())
+            }
+        }
+    }
+
+    /// Prints how many duplicates were found and how much space was saved
+    pub fn print_summary(&self) {
+        if self.duplicates == 0 {
+            return;
+        }
+        match self.strategy {
+            Dedupe::Copy => eprintln!(
+                "Found {} winmd(s) identical to one of another package, pass --dedupe skip-duplicates or --dedupe hardlink to save space",
+                self.duplicates
+            ),
+            _ => eprintln!(
+                "Found {} winmd(s) identical to one of another package, saving {} bytes",
+                self.duplicates, self.saved
+            ),
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Every warning printed so far
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Prints a warning to stderr
+///
+/// All warnings go through here so they can be included in the install report.
+pub fn warn(message: String) {
+    eprintln!("warning: {}", message);
+    WARNINGS.lock().unwrap().push(message);
+}
+
+/// The warnings printed so far, in order
+pub fn warnings() -> Vec<String> {
+    WARNINGS.lock().unwrap().clone()
+}
+
+/// Set once an error of a single package was reported
+static PACKAGE_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Whether an error of a single package was reported, in which case the error failing the
+/// whole install is that one or a list of those and doesn't need to be reported again
+pub fn package_failed() -> bool {
+    PACKAGE_FAILED.load(Ordering::SeqCst)
+}
+
+/// How progress is reported
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Human,
+    /// One JSON object per line for every event as it happens
+    JsonLines,
+}
+
+impl OutputFormat {
+    pub const VARIANTS: &'static [&'static str] = &["human", "json-lines"];
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(OutputFormat::Human),
+            "json-lines" => Ok(OutputFormat::JsonLines),
+            _ => Err(format!("unknown output format `{}`", s)),
+        }
+    }
+}
+
+/// Something that happened during an install
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    /// A package started downloading
+    Started { package: &'a str, version: &'a str },
+    /// A package finished downloading
+    Downloaded {
+        package: &'a str,
+        version: &'a str,
+        bytes: usize,
+    },
+    /// A package's files were written to the output directory
+    Extracted {
+        package: &'a str,
+        version: &'a str,
+        winmds: usize,
+        dlls: usize,
+        docs: usize,
+        symbols: usize,
+        runtimes: usize,
+        tools: usize,
+    },
+    /// Every package was installed
+    Finished { packages: usize },
+    /// Something went wrong, either with a single package or the whole install
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        package: Option<&'a str>,
+        /// The error's stable code, see `Error::code`
+        code: &'static str,
+        message: String,
+    },
+}
+
+/// Reports events in the chosen output format
+#[derive(Debug, Clone, Copy)]
+pub struct Events {
+    format: OutputFormat,
+}
+
+impl Events {
+    pub fn new(format: OutputFormat) -> Self {
+        Self { format }
+    }
+
+    pub fn emit(&self, event: Event) {
+        if let Event::Error {
+            package: Some(_), ..
+        } = &event
+        {
+            PACKAGE_FAILED.store(true, Ordering::SeqCst);
+        }
+        if self.format == OutputFormat::JsonLines {
+            // Serializing these types can't fail
+            println!("{}", serde_json::to_string(&event).unwrap());
+        }
+    }
+}
+
+/// A line on stderr counting the packages that are done, followed by a bar for every
+/// package being extracted, redrawn as anything advances
+#[derive(Debug)]
+pub struct Progress {
+    total: usize,
+    state: Mutex<ProgressState>,
+}
+
+#[derive(Debug, Default)]
+struct ProgressState {
+    done: usize,
+    /// The name, processed entries and total entries of every package being extracted
+    extracting: Vec<(String, usize, usize)>,
+    /// The length of the line last drawn, so a shorter one can overwrite all of it
+    width: usize,
+}
+
+impl Progress {
+    pub fn new(total: usize) -> Self {
+        Self {
+            total,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Whether a progress line should be drawn when neither `--progress` nor `--no-progress`
+    /// was passed
+    ///
+    /// CI systems capture stderr even when it looks like a terminal, and every redraw would
+    /// end up as a separate line in their logs.
+    pub fn is_wanted() -> bool {
+        use std::io::IsTerminal;
+        let in_ci = ["CI", "GITHUB_ACTIONS", "TF_BUILD"]
+            .iter()
+            .any(|var| std::env::var_os(var).is_some_and(|v| !v.is_empty() && v != "false"));
+        std::io::stderr().is_terminal() && !in_ci
+    }
+
+    /// Counts another package as done
+    pub fn tick(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.done += 1;
+        self.draw(&mut state);
+    }
+
+    /// Starts showing a bar for a package whose archives have `entries` entries in total
+    pub fn extraction(self: &Arc<Self>, name: &str, entries: usize) -> Extraction {
+        let mut state = self.state.lock().unwrap();
+        state.extracting.push((name.to_owned(), 0, entries));
+        self.draw(&mut state);
+        Extraction {
+            progress: self.clone(),
+            name: name.to_owned(),
+        }
+    }
+
+    fn draw(&self, state: &mut ProgressState) {
+        let mut line = format!("Installing packages: {}/{}", state.done, self.total);
+        for (name, processed, entries) in &state.extracting {
+            let filled = filled(*processed, *entries);
+            line.push_str(&format!(
+                " | {} [{}{}]",
+                name,
+                "#".repeat(filled),
+                " ".repeat(BAR_WIDTH - filled)
+            ));
+        }
+        let width = line.chars().count();
+        let mut stderr = std::io::stderr();
+        let _ = write!(
+            stderr,
+            "\r{}{}",
+            line,
+            " ".repeat(state.width.saturating_sub(width))
+        );
+        let _ = stderr.flush();
+        state.width = width;
+    }
+
+    /// Ends the progress line so nothing else is printed on it
+    pub fn finish(&self) {
+        if self.state.lock().unwrap().width > 0 {
+            eprintln!();
+        }
+    }
+}
+
+const BAR_WIDTH: usize = 10;
+
+/// How much of a bar is filled once `processed` of `entries` entries are
+fn filled(processed: usize, entries: usize) -> usize {
+    (processed * BAR_WIDTH)
+        .checked_div(entries)
+        .unwrap_or(BAR_WIDTH)
+}
+
+/// A package's bar on the progress line, removed again once this is dropped
+#[derive(Debug)]
+pub struct Extraction {
+    progress: Arc<Progress>,
+    name: String,
+}
+
+impl Extraction {
+    /// Counts another archive entry as processed
+    pub fn advance(&self) {
+        let mut state = self.progress.state.lock().unwrap();
+        let entry = match state.extracting.iter_mut().find(|e| e.0 == self.name) {
+            Some(e) => e,
+            None => return,
+        };
+        let before = filled(entry.1, entry.2);
+        entry.1 = (entry.1 + 1).min(entry.2);
+        // Packages can have thousands of entries so only redraw when the bar grows
+        if filled(entry.1, entry.2) != before {
+            self.progress.draw(&mut state);
+        }
+    }
+}
+
+impl Drop for Extraction {
+    fn drop(&mut self) {
+        let mut state = self.progress.state.lock().unwrap();
+        state.extracting.retain(|e| e.0 != self.name);
+        self.progress.draw(&mut state);
+    }
+}
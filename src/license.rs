@@ -0,0 +1,223 @@
+use crate::nuspec::License;
+
+/// Where nuget.org points the `licenseUrl` of packages with a license expression, followed
+/// by the expression itself
+const NUGET_LICENSE_URL: &str = "https://licenses.nuget.org/";
+
+/// The SPDX licenses packages may be under, see `--allowed-licenses`
+#[derive(Debug)]
+pub struct LicensePolicy {
+    allowed: Vec<String>,
+}
+
+impl LicensePolicy {
+    pub fn new(allowed: Vec<String>) -> Self {
+        Self { allowed }
+    }
+
+    /// Whether a package with the given license may be installed
+    ///
+    /// Only license expressions can be checked, so license files and urls other than
+    /// nuget.org's aren't allowed, and neither are packages without any license.
+    pub fn allows(&self, license: Option<&License>) -> bool {
+        let expression = match license {
+            Some(License::Expression(expression)) => expression.clone(),
+            Some(License::Url(url)) if url.starts_with(NUGET_LICENSE_URL) => {
+                percent_decode(&url[NUGET_LICENSE_URL.len()..])
+            }
+            _ => return false,
+        };
+        let tokens = tokenize(&expression);
+        let mut parser = Parser {
+            tokens: &tokens,
+            position: 0,
+            allowed: &self.allowed,
+        };
+        // An expression that can't be parsed can't be shown to be allowed
+        match parser.or() {
+            Some(allowed) => allowed && parser.position == tokens.len(),
+            None => false,
+        }
+    }
+}
+
+/// Splits an expression into parentheses and words, i.e. license ids and operators
+fn tokenize(expression: &str) -> Vec<String> {
+    expression
+        .replace('(', " ( ")
+        .replace(')', " ) ")
+        .split_whitespace()
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Evaluates an SPDX expression, in which AND binds more tightly than OR
+struct Parser<'a> {
+    tokens: &'a [String],
+    position: usize,
+    allowed: &'a [String],
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(String::as_str)
+    }
+
+    /// Skips the next token if it's the given operator
+    fn skip_operator(&mut self, operator: &str) -> bool {
+        let found = matches!(self.peek(), Some(t) if t.eq_ignore_ascii_case(operator));
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn next(&mut self) -> Option<&str> {
+        let token = self.tokens.get(self.position)?;
+        self.position += 1;
+        Some(token)
+    }
+
+    /// Either of the alternatives has to be allowed
+    fn or(&mut self) -> Option<bool> {
+        let mut allowed = self.and()?;
+        while self.skip_operator("OR") {
+            allowed |= self.and()?;
+        }
+        Some(allowed)
+    }
+
+    /// Every one of the licenses has to be allowed
+    fn and(&mut self) -> Option<bool> {
+        let mut allowed = self.license()?;
+        while self.skip_operator("AND") {
+            allowed &= self.license()?;
+        }
+        Some(allowed)
+    }
+
+    /// A parenthesized expression or a license id, optionally with an exception
+    fn license(&mut self) -> Option<bool> {
+        if self.peek() == Some("(") {
+            self.position += 1;
+            let allowed = self.or()?;
+            return Some(allowed).filter(|_| self.next() == Some(")"));
+        }
+        let id = self.next()?.to_owned();
+        let operators = ["AND", "OR", "WITH"];
+        if id == ")" || operators.iter().any(|o| id.eq_ignore_ascii_case(o)) {
+            return None;
+        }
+        let mut allowed = self.is_allowed(&id);
+        if self.skip_operator("WITH") {
+            let exception = self.next()?.to_owned();
+            // Exceptions only grant more permissions, but can be allowed on their own too
+            allowed |= self.is_allowed(&format!("{} WITH {}", id, exception));
+        }
+        Some(allowed)
+    }
+
+    fn is_allowed(&self, id: &str) -> bool {
+        self.allowed.iter().any(|a| a.eq_ignore_ascii_case(id))
+    }
+}
+
+/// Decodes the `%XX` escapes of a url path
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allows(allowed: &[&str], expression: &str) -> bool {
+        let policy = LicensePolicy::new(allowed.iter().map(|a| a.to_string()).collect());
+        policy.allows(Some(&License::Expression(expression.to_owned())))
+    }
+
+    #[test]
+    fn either_alternative_of_or() {
+        assert!(allows(&["MIT"], "MIT OR Apache-2.0"));
+        assert!(allows(&["Apache-2.0"], "MIT OR Apache-2.0"));
+        assert!(!allows(&["BSD-3-Clause"], "MIT OR Apache-2.0"));
+        assert!(allows(&["mit"], "MIT or Apache-2.0"));
+    }
+
+    #[test]
+    fn and_binds_more_tightly_than_or() {
+        let expression = "(MIT AND BSD-3-Clause) OR GPL-2.0";
+        assert!(allows(&["MIT", "BSD-3-Clause"], expression));
+        assert!(allows(&["GPL-2.0"], expression));
+        assert!(!allows(&["MIT"], expression));
+        assert!(!allows(&["BSD-3-Clause"], expression));
+        // Without parentheses the same licenses are grouped the same way
+        assert!(allows(&["GPL-2.0"], "MIT AND BSD-3-Clause OR GPL-2.0"));
+        assert!(!allows(&["MIT"], "MIT AND BSD-3-Clause OR GPL-2.0"));
+    }
+
+    #[test]
+    fn exceptions() {
+        let expression = "GPL-2.0 WITH Classpath-exception-2.0";
+        assert!(allows(&["GPL-2.0"], expression));
+        assert!(allows(
+            &["GPL-2.0 WITH Classpath-exception-2.0"],
+            expression
+        ));
+        assert!(!allows(&["Classpath-exception-2.0"], expression));
+        assert!(!allows(&["GPL-2.0"], "GPL-2.0 WITH"));
+    }
+
+    #[test]
+    fn malformed_expressions_are_not_allowed() {
+        let allowed = ["MIT", "Apache-2.0"];
+        assert!(!allows(&allowed, "(MIT OR Apache-2.0"));
+        assert!(!allows(&allowed, "MIT OR Apache-2.0)"));
+        assert!(!allows(&allowed, "MIT OR"));
+        assert!(!allows(&allowed, "AND MIT"));
+        assert!(!allows(&allowed, "MIT Apache-2.0"));
+        assert!(!allows(&allowed, ""));
+    }
+
+    #[test]
+    fn nuget_license_urls() {
+        let policy = LicensePolicy::new(vec!["MIT".to_owned(), "Apache-2.0".to_owned()]);
+        let url = |url: &str| Some(License::Url(url.to_owned()));
+        assert!(policy.allows(url("https://licenses.nuget.org/MIT").as_ref()));
+        assert!(policy.allows(url("https://licenses.nuget.org/MIT%20OR%20GPL-2.0").as_ref()));
+        assert!(policy.allows(url("https://licenses.nuget.org/(MIT%20AND%20Apache-2.0)").as_ref()));
+        assert!(!policy.allows(url("https://licenses.nuget.org/GPL-2.0").as_ref()));
+        // Other urls can't be checked
+        assert!(!policy.allows(url("https://example.com/MIT").as_ref()));
+        assert!(!policy.allows(Some(&License::File("LICENSE.txt".to_owned()))));
+        assert!(!policy.allows(None));
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("MIT%20OR%20Apache-2.0"), "MIT OR Apache-2.0");
+        assert_eq!(percent_decode("%28MIT%29"), "(MIT)");
+        // Escapes that aren't complete are kept as they are
+        assert_eq!(percent_decode("MIT%2"), "MIT%2");
+        assert_eq!(percent_decode("MIT%zz"), "MIT%zz");
+    }
+}
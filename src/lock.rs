@@ -0,0 +1,85 @@
+use crate::Error;
+
+use serde::{Deserialize, Serialize};
+
+use std::path::Path;
+
+/// The name of the lock file written next to Cargo.toml
+pub const LOCK_FILE_NAME: &str = "nuget.lock";
+
+const HEADER: &str = "# This file is automatically generated by cargo nuget.\n\
+                      # It is not intended for manual editing.\n";
+
+/// A record of exactly what was installed for each dependency
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Lockfile {
+    #[serde(default, rename = "package")]
+    pub packages: Vec<LockedPackage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockedPackage {
+    pub name: String,
+    pub version: String,
+    /// The url of the source the package was downloaded from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// The names of the winmds extracted from the package, sorted
+    #[serde(default)]
+    pub winmds: Vec<String>,
+    /// The SHA-256 hash of the nupkg, missing for packages locked before hashes were recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// The winmds extracted for each framework, for packages installed with `--framework`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub frameworks: Vec<LockedFramework>,
+}
+
+/// The winmds extracted into a package's folder for one framework
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockedFramework {
+    pub framework: String,
+    /// The winmds by their name inside the framework's folder, sorted
+    pub winmds: Vec<LockedFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockedFile {
+    pub name: String,
+    pub sha256: String,
+}
+
+impl Lockfile {
+    /// Reads the lock file at `path`, returning `None` if there isn't one
+    pub fn load(path: &Path) -> Result<Option<Self>, Error> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::ReadFailed(path.to_owned(), e)),
+        };
+        toml::from_str(&contents)
+            .map(Some)
+            .map_err(|_| Error::MalformedLockfile(path.to_owned()))
+    }
+
+    pub fn save(&mut self, path: &Path) -> Result<(), Error> {
+        self.packages.sort_by(|a, b| a.name.cmp(&b.name));
+        let contents = toml::to_string(self).map_err(Error::SerializeLockfile)?;
+        std::fs::write(path, format!("{}{}", HEADER, contents))
+            .map_err(|e| Error::WriteFailed(path.to_owned(), e))
+    }
+
+    /// Records newly installed packages, keeping entries for packages that are still
+    /// declared but weren't installed this time
+    pub fn update(&mut self, installed: Vec<LockedPackage>, declared: &[String]) {
+        self.packages
+            .retain(|p| declared.contains(&p.name) && !installed.iter().any(|i| i.name == p.name));
+        self.packages.extend(installed);
+    }
+
+    pub fn find(&self, name: &str, version: &str) -> Option<&LockedPackage> {
+        self.packages
+            .iter()
+            .find(|p| p.name == name && p.version == version)
+    }
+}
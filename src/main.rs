@@ -1,16 +1,280 @@
+mod cache;
+mod concurrency;
+mod dedupe;
+mod events;
+mod license;
+mod lock;
+mod nuspec;
+mod report;
+mod sbom;
+mod schema;
+mod snapshot;
+mod source;
+mod spool;
+mod store;
+mod suggest;
+mod throttle;
+mod vendor;
+
 use cargo_toml::{Manifest, Value};
-use futures::future::{BoxFuture, FutureExt};
+use futures::future::{BoxFuture, Either, FutureExt};
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use structopt::clap::{AppSettings, Shell};
 use structopt::StructOpt;
 use thiserror::Error;
 
-use std::ffi::OsString;
+use cache::{Cache, Validators};
+use concurrency::Concurrency;
+use dedupe::{Dedupe, Deduper};
+use events::{warn, Event, Events, Extraction, OutputFormat, Progress};
+use license::LicensePolicy;
+use lock::{LockedFile, LockedFramework, LockedPackage, Lockfile};
+use nuspec::FloatingVersion;
+use nuspec::{License, Nuspec};
+use report::{Environment, FileReport, PackageReport, Report};
+use reqwest::header::{HeaderName, HeaderValue};
+use sbom::{Component, Sbom};
+use schema::SchemaKind;
+use schemars::JsonSchema;
+use sha2::{Digest, Sha256};
+use snapshot::{Snapshot, SnapshotPackage};
+use source::{ListedVersion, PackageBase, PackageKind, Source, SourceConfig};
+use spool::Spool;
+use std::convert::TryFrom;
+use std::ffi::{OsStr, OsString};
 use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use store::Store;
+use throttle::Throttle;
+use vendor::{VendorManifest, VendoredPackage};
 
 fn main() {
     let Opt::Nuget { subcommand } = Opt::from_args();
-    match subcommand {
-        Subcommand::Install(i) => i.perform().unwrap(),
+    let result = match subcommand {
+        Subcommand::Install {
+            packages,
+            mut install,
+        } => {
+            install.packages = packages;
+            install.perform()
+        }
+        Subcommand::ImportCache(mut i) => i.import_cache(),
+        Subcommand::Reinstall(mut i) => i.reinstall(),
+        Subcommand::ExportSnapshot { path, install } => install.export_snapshot(&path),
+        Subcommand::Contents {
+            name,
+            version,
+            install,
+        } => install.print_contents(name, version),
+        Subcommand::Frameworks {
+            name,
+            version,
+            json,
+            install,
+        } => install.print_frameworks(name, version, json),
+        Subcommand::Add {
+            name,
+            version,
+            upgrade,
+            install,
+        } => install.add_dependency(name, version, upgrade),
+        Subcommand::Remove {
+            name,
+            delete_files,
+            mut install,
+        } => install.remove_dependency(name, delete_files),
+        Subcommand::Explain { name, install } => install.explain(&name),
+        Subcommand::ListInstalled { json, mut install } => install.list_installed(json),
+        Subcommand::Cache { command, cache_dir } => manage_cache(command, cache_dir),
+        Subcommand::JsonSchema { kind } => {
+            schema::print(kind);
+            Ok(())
+        }
+        Subcommand::Completions { shell } => {
+            Opt::clap().gen_completions_to("cargo", shell, &mut std::io::stdout());
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("error[{}]: {}", e.code(), e);
+        // Shells report commands killed by Ctrl-C with 128 + SIGINT
+        let code = if let Error::Interrupted = e { 130 } else { 1 };
+        std::process::exit(code);
+    }
+}
+
+/// Runs a `cache` subcommand on the given cache directory, or the default one
+fn manage_cache(command: CacheCommand, dir: Option<PathBuf>) -> Result<(), Error> {
+    let cache = dir
+        .or_else(Cache::default_dir)
+        .map(Cache::new)
+        .ok_or(Error::NoCacheDir)?;
+    let dir = cache.dir().to_owned();
+    let now = SystemTime::now();
+    // Ages are only shown to the day, or the hour for recent ones
+    let age = |accessed: SystemTime| {
+        let secs = now.duration_since(accessed).unwrap_or_default().as_secs();
+        let rounded = if secs < 86_400 {
+            secs - secs % 3_600
+        } else {
+            secs - secs % 86_400
+        };
+        match rounded {
+            0 => "less than an hour".to_owned(),
+            secs => humantime::format_duration(std::time::Duration::from_secs(secs)).to_string(),
+        }
+    };
+    match command {
+        CacheCommand::List => {
+            let entries = cache.entries().map_err(read_error(&dir))?;
+            for entry in &entries {
+                println!(
+                    "{:>10}  {} {}  (last used {} ago)",
+                    entry.size,
+                    entry.name,
+                    entry.version,
+                    age(entry.accessed)
+                );
+            }
+            let total: u64 = entries.iter().map(|e| e.size).sum();
+            println!(
+                "{:>10}  {} package(s) in {}",
+                total,
+                entries.len(),
+                dir.display()
+            );
+        }
+        CacheCommand::Clean => {
+            cache.clean().map_err(write_error(&dir))?;
+            eprintln!("Removed {}", dir.display());
+        }
+        CacheCommand::Gc { older_than } => {
+            let entries = cache.entries().map_err(read_error(&dir))?;
+            let (mut removed, mut freed) = (0, 0);
+            for entry in entries {
+                if now.duration_since(entry.accessed).unwrap_or_default() < older_than {
+                    continue;
+                }
+                let path = cache.path(&entry.name, &entry.version);
+                cache
+                    .remove(&entry.name, &entry.version)
+                    .map_err(write_error(&path))?;
+                eprintln!(
+                    "Removed {} {}, last used {} ago",
+                    entry.name,
+                    entry.version,
+                    age(entry.accessed)
+                );
+                removed += 1;
+                freed += entry.size;
+            }
+            eprintln!("Removed {} package(s), freeing {} bytes", removed, freed);
+        }
+    }
+    Ok(())
+}
+
+/// Replaces sources that have mirrors by the source and its mirrors, ordered by how quickly
+/// they respond, see `Source::by_health`
+///
+/// The mirrors are only probed once per run.
+fn order_mirrors(sources: Vec<Source>, client: &reqwest::Client) -> Result<Vec<Source>, Error> {
+    if !sources.iter().any(Source::has_mirrors) {
+        return Ok(sources);
+    }
+    let mut runtime = new_runtime()?;
+    let ordered = sources.into_iter().map(|source| async move {
+        if source.has_mirrors() {
+            source.by_health(client).await
+        } else {
+            vec![source]
+        }
+    });
+    Ok(runtime
+        .block_on(futures::future::join_all(ordered))
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+/// Starts a runtime to block on until the downloads are done
+///
+/// Blocking on a runtime from within another one panics, which is reported as an error
+/// instead.
+fn new_runtime() -> Result<tokio::runtime::Runtime, Error> {
+    if tokio::runtime::Handle::try_current().is_ok() {
+        return Err(Error::NestedRuntime);
+    }
+    tokio::runtime::Runtime::new().map_err(Error::Runtime)
+}
+
+/// Set once Ctrl-C has been pressed during an install
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Fails if Ctrl-C has been pressed, so work can stop at a point where nothing is half written
+fn check_interrupted() -> Result<(), Error> {
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        return Err(Error::Interrupted);
+    }
+    Ok(())
+}
+
+/// Resolves when Ctrl-C is pressed
+///
+/// If the handler can't be installed Ctrl-C just kills the process as usual so this
+/// never resolves.
+async fn ctrl_c() {
+    if tokio::signal::ctrl_c().await.is_err() {
+        futures::future::pending::<()>().await;
+    }
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// When `--timeout-overall` aborts the install
+#[derive(Debug, Clone, Copy)]
+struct Deadline {
+    at: std::time::Instant,
+    /// The timeout that was given, for the error message
+    secs: u64,
+}
+
+impl Deadline {
+    fn after(secs: u64) -> Self {
+        Self {
+            at: std::time::Instant::now() + std::time::Duration::from_secs(secs),
+            secs,
+        }
+    }
+
+    /// Fails if the deadline has passed, so work can stop at a point where nothing is half
+    /// written like `check_interrupted`
+    fn check(&self) -> Result<(), Error> {
+        if std::time::Instant::now() >= self.at {
+            return Err(Error::TimedOut(self.secs));
+        }
+        Ok(())
+    }
+}
+
+/// Runs `future` until it's done or the deadline passes, whichever comes first
+///
+/// The future is dropped once the deadline passes, which removes the spool files of
+/// downloads that were still in flight.
+async fn within<T>(
+    deadline: Option<Deadline>,
+    future: impl std::future::Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    match deadline {
+        Some(deadline) => {
+            tokio::time::timeout_at(tokio::time::Instant::from_std(deadline.at), future)
+                .await
+                .unwrap_or(Err(Error::TimedOut(deadline.secs)))
+        }
+        None => future.await,
     }
 }
 
@@ -26,249 +290,5384 @@ enum Opt {
 }
 
 #[derive(Debug, StructOpt)]
+// Subcommands are only parsed once so their size doesn't matter
+#[allow(clippy::large_enum_variant)]
 enum Subcommand {
-    Install(Install),
+    Install {
+        /// Install only these packages at these versions, written as `name@version`, instead
+        /// of the dependencies of Cargo.toml, which is left untouched
+        ///
+        /// Options the manifest declares for a package, like its paths, still apply.
+        #[structopt(name = "PACKAGE@VERSION", parse(try_from_str = parse_package_version))]
+        packages: Vec<(String, String)>,
+        #[structopt(flatten)]
+        install: Install,
+    },
+    /// Add the packages already installed in the output directory to the cache
+    ///
+    /// Every package the lock file records as installed is downloaded once more from the
+    /// source it was installed from and only cached if it contains exactly the installed
+    /// winmds. Packages that can't be matched this way are skipped. Takes the same options
+    /// as install so packages are found and extracted the same way.
+    ImportCache(Install),
+    /// Extract the packages recorded in the lock file again, taking them from the cache
+    ///
+    /// This restores a clobbered output directory without contacting the sources, which are
+    /// only used for packages that aren't cached anymore. Takes the same options as install.
+    Reinstall(Install),
+    /// Save the versions the sources publish of every dependency to a file, which
+    /// --registration-snapshot resolves floating versions against without the sources
+    ///
+    /// Takes the same options as install, so with --as-of only the versions published by then
+    /// are saved.
+    ExportSnapshot {
+        /// The file to save the snapshot to
+        #[structopt(parse(try_from_os_str = expand_path))]
+        path: PathBuf,
+        #[structopt(flatten)]
+        install: Install,
+    },
+    /// Print the path and size of every file inside a package without extracting anything
+    ///
+    /// The package is downloaded from the same sources as by install, which is useful to
+    /// find the right --framework or `paths` for an unfamiliar package.
+    Contents {
+        /// The id of the package
+        name: String,
+        /// The version of the package
+        version: String,
+        #[structopt(flatten)]
+        install: Install,
+    },
+    /// Print the target frameworks a package has files for in its lib, ref and runtimes
+    /// folders
+    ///
+    /// The package is downloaded from the same sources as by install, which is useful to
+    /// pick --framework for an unfamiliar package.
+    Frameworks {
+        /// The id of the package
+        name: String,
+        /// The version of the package
+        version: String,
+        /// Print the frameworks as a JSON object instead
+        #[structopt(long)]
+        json: bool,
+        #[structopt(flatten)]
+        install: Install,
+    },
+    /// Add a dependency to the nuget_dependencies table of Cargo.toml
+    ///
+    /// Without a version the latest stable version is looked up in the sources. The rest of
+    /// the manifest is kept as it was, including comments and formatting.
+    Add {
+        /// The id of the package
+        name: String,
+        /// The version of the package, the latest stable version if none is given
+        version: Option<String>,
+        /// Change the version of the dependency if it already exists
+        #[structopt(long)]
+        upgrade: bool,
+        #[structopt(flatten)]
+        install: Install,
+    },
+    /// Remove a dependency from the nuget_dependencies table of Cargo.toml and the lock file
+    Remove {
+        /// The id of the package
+        name: String,
+        /// Also delete the dependency's directory in the output directory
+        #[structopt(long)]
+        delete_files: bool,
+        #[structopt(flatten)]
+        install: Install,
+    },
+    /// Explain why a dependency is installed at the version it is
+    ///
+    /// Prints where the dependency is declared, the versions published in each source that
+    /// were considered for a floating version, which of them match and why the chosen one
+    /// won, and what the lock file recorded.
+    Explain {
+        /// The id of the dependency
+        name: String,
+        #[structopt(flatten)]
+        install: Install,
+    },
+    /// List the packages installed in the output directory with their version and number of
+    /// files
+    ///
+    /// The output directory is scanned as it is, independent of the manifest, which shows
+    /// what's there after manual edits or a partial install. Versions are taken from the lock
+    /// file.
+    ListInstalled {
+        /// Print the packages as a JSON array instead
+        #[structopt(long)]
+        json: bool,
+        #[structopt(flatten)]
+        install: Install,
+    },
+    /// Inspect the cache of downloaded packages or remove packages from it
+    Cache {
+        #[structopt(subcommand)]
+        command: CacheCommand,
+        /// The directory downloaded packages are cached in
+        #[structopt(long, global = true, parse(try_from_os_str = expand_path))]
+        cache_dir: Option<PathBuf>,
+    },
+    /// Print the JSON Schema of a machine readable output to stdout
+    ///
+    /// The schemas are generated from the types the outputs are written from, so they always
+    /// match this version of cargo-nuget.
+    #[structopt(setting = AppSettings::Hidden)]
+    JsonSchema {
+        #[structopt(possible_values = SchemaKind::VARIANTS)]
+        kind: SchemaKind,
+    },
+    /// Print a completion script for the given shell to stdout
+    #[structopt(setting = AppSettings::Hidden)]
+    Completions {
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum CacheCommand {
+    /// List the cached packages with their size and when they were last used
+    List,
+    /// Remove every cached package and the cached feed metadata
+    Clean,
+    /// Remove the cached packages that haven't been installed or downloaded for a while
+    Gc {
+        /// How long a package has to be unused to be removed, e.g. `30days` or `2weeks`
+        #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+        older_than: std::time::Duration,
+    },
 }
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 enum Error {
     #[error("No Cargo.toml could be found")]
     NoCargoToml,
-    #[error("There was an error downloading the NuGet package {0}")]
-    DownloadError(Box<dyn std::error::Error>),
-    #[error("The Cargo.toml file was malformed")]
-    MalformedManifest,
-    #[error("There was some other error {0}")]
-    Other(Box<dyn std::error::Error>),
+    // reqwest's messages already include the url
+    #[error("{1}")]
+    Network(String, reqwest::Error),
+    #[error("The request to {url} failed with status {status}")]
+    HttpStatus { url: String, status: u16 },
+    #[error("The request to {url} succeeded with status {status} but without any content")]
+    EmptyResponse { url: String, status: u16 },
+    #[error("The download of {url} ended after {received} of {expected} bytes")]
+    TruncatedDownload {
+        url: String,
+        expected: u64,
+        received: usize,
+    },
+    #[error("The request to {0} was redirected too many times")]
+    TooManyRedirects(String),
+    #[error("The feed {0} is malformed: {1}")]
+    MalformedFeed(String, String),
+    #[error("The source {0} doesn't record when versions were published, which --as-of needs")]
+    UndatedSource(String),
+    #[error("The local source {0} is not a directory")]
+    MissingLocalSource(PathBuf),
+    #[error("The Cargo.toml file was malformed: {0}")]
+    MalformedManifest(String),
+    #[error("A manifest read from stdin can't be edited")]
+    StdinManifest,
+    #[error("The NuGet package {0} is larger than the maximum download size of {1} bytes")]
+    DownloadTooLarge(String, u64),
+    #[error("The file pattern {0} is not a valid glob")]
+    InvalidPattern(String),
+    #[error("The lock file {0} is malformed")]
+    MalformedLockfile(PathBuf),
+    #[error("The registration snapshot {0} is malformed")]
+    MalformedSnapshot(PathBuf),
+    #[error("The registration snapshot {} has no versions of {0}, export it again", .1.display())]
+    NotInSnapshot(String, PathBuf),
+    #[error("The vendor manifest {0} is malformed")]
+    MalformedVendorManifest(PathBuf),
+    #[error("{} has no vendor manifest, install with --vendor first", .0.display())]
+    NoVendorManifest(PathBuf),
+    #[error("The dependency {0} isn't vendored, install with --vendor again")]
+    NotVendored(String),
+    #[error("The vendored package {0} {1} doesn't match the manifest and nuget.lock, install with --vendor again")]
+    VendorOutdated(String, String),
+    #[error("The vendored file {} was changed since it was installed with --vendor", .0.display())]
+    VendoredFileChanged(PathBuf),
+    #[error(
+        "The package {name} {version} extracted {actual:?} but the lock file recorded {expected:?}"
+    )]
+    ExtractionMismatch {
+        name: String,
+        version: String,
+        expected: Vec<String>,
+        actual: Vec<String>,
+    },
+    #[error("The winmds the package {name} {version} extracted for {framework} differ from those the lock file recorded: {}", .differing.join(", "))]
+    FrameworkMismatch {
+        name: String,
+        version: String,
+        framework: String,
+        /// The winmds that were added, removed or changed
+        differing: Vec<String>,
+    },
+    #[error("The NuGet package {name} {version} has the SHA-256 hash {actual} but the lock file recorded {expected}, pass --ignore-hash to install it anyway")]
+    HashMismatch {
+        name: String,
+        version: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("The NuGet package {name} {version} requires NuGet {required} but only the features of NuGet {} are supported, so it may not be extracted correctly", nuspec::SUPPORTED_CLIENT_VERSION)]
+    NewerClientRequired {
+        name: String,
+        version: String,
+        required: String,
+    },
+    #[error("The NuGet package {0} is invalid because {1}")]
+    InvalidPackage(String, String),
+    #[error("The NuGet package {0} is not a valid zip archive: {1}")]
+    Zip(String, zip::result::ZipError),
+    #[error("Entry {index} of the NuGet package {name} can't be read, pass --continue-on-extract-error to skip it: {error}")]
+    CorruptEntry {
+        name: String,
+        index: usize,
+        error: zip::result::ZipError,
+    },
+    #[error("{path} of the NuGet package {name} can't be extracted, pass --continue-on-extract-error to skip it: {error}")]
+    ExtractFailed {
+        name: String,
+        path: String,
+        error: std::io::Error,
+    },
+    #[error("Requested the NuGet package {requested} but received {received}")]
+    PackageMismatch { requested: String, received: String },
+    #[error("The NuGet package {name} is expected to be authored by {} but its authors are {}", .expected.join(", "), list_or_none(.actual))]
+    UnexpectedAuthors {
+        name: String,
+        expected: Vec<String>,
+        actual: Vec<String>,
+    },
+    #[error("The NuGet package {name} {version} is licensed under {} which --allowed-licenses doesn't allow", license_or_none(.license))]
+    LicenseNotAllowed {
+        name: String,
+        version: String,
+        license: Option<License>,
+    },
+    #[error("The dependency `{0}` is not a valid NuGet package id")]
+    InvalidPackageId(String),
+    #[error("The NuGet package {0} could not be found in any source")]
+    PackageNotFound(String),
+    #[error("The NuGet package {name} could not be found in any source; did you mean {}?", .suggestions.join(" or "))]
+    PackageNotFoundSuggestions {
+        name: String,
+        suggestions: Vec<String>,
+    },
+    #[error("The NuGet package {0} can't be installed because its directory would be outside of the output directory")]
+    UnsafePackageDir(String),
+    #[error("nuget.lock records no hash for {0} {1} to verify it against")]
+    NoLockedHash(String, String),
+    #[error("The proxy {0} is not a valid url, or `none` to connect directly")]
+    InvalidProxy(String),
+    #[error("The credential helper failed to provide a token for {url}: {reason}")]
+    CredentialHelper { url: String, reason: String },
+    #[error("The environment variable {0} referenced by the manifest is not set")]
+    MissingEnvVar(String),
+    #[error("The url {0} uses unencrypted http, pass --allow-insecure-http to allow it")]
+    InsecureHttp(String),
+    #[error(
+        "The dependency {0} is inherited from the workspace but the workspace doesn't declare it"
+    )]
+    MissingWorkspaceDependency(String),
+    #[error(
+        "The lock file requires {0} to be downloaded from {1} which isn't a configured source"
+    )]
+    UnknownLockedSource(String, String),
+    #[error("The NuGet package {0} could not be found in {1} which the lock file requires it to be downloaded from")]
+    LockedSourceMismatch(String, String),
+    #[error("The NuGet package {name} has no winmds for {framework}; available frameworks: {}", list_or_none(.available))]
+    MissingFramework {
+        name: String,
+        framework: String,
+        available: Vec<String>,
+    },
+    #[error("The dependency {0} already exists, pass --upgrade to change its version")]
+    DependencyExists(String),
+    #[error("The dependency {0} is not in Cargo.toml")]
+    NoSuchDependency(String),
+    #[error("No published version of the NuGet package {0} matches {1}")]
+    NoMatchingVersion(String, String),
+    #[error("{0} warning(s) were emitted, which --deny-warnings turns into an error")]
+    DeniedWarnings(usize),
+    #[error("The temporary directory {0} is not writable: {1}")]
+    TempDirNotWritable(PathBuf, std::io::Error),
+    #[error("--use-out-dir needs the OUT_DIR environment variable, which cargo only sets while running build scripts")]
+    NoOutDir,
+    #[error("Invalid header: {0}")]
+    InvalidHeader(String),
+    #[error("Unknown resource type {0}, only PackageBaseAddress, RegistrationsBaseUrl and SearchQueryService can be overridden")]
+    UnknownResourceType(String),
+    #[error("{0} could not be read: {1}")]
+    ReadFailed(PathBuf, std::io::Error),
+    #[error("{}", describe_write_error(.0, .1))]
+    WriteFailed(PathBuf, std::io::Error),
+    #[error("No cache directory could be found")]
+    NoCacheDir,
+    #[error("The lock file could not be serialized: {0}")]
+    SerializeLockfile(toml::ser::Error),
+    #[error("The {0} could not be serialized: {1}")]
+    Serialize(&'static str, toml::ser::Error),
+    #[error("The http client could not be created: {0}")]
+    Client(reqwest::Error),
+    #[error("The TLS connector could not be created: {0}")]
+    Tls(native_tls::Error),
+    #[error("The secure connection to {0} could not be established, it may only offer TLS versions older than --min-tls allows: {1}")]
+    TlsHandshake(String, reqwest::Error),
+    #[error("The async runtime could not be started: {0}")]
+    Runtime(std::io::Error),
+    #[error("Installs run on a runtime of their own and can't be started from within a tokio runtime, start them from a thread of their own instead")]
+    NestedRuntime,
+    #[error("A background task failed: {0}")]
+    Task(tokio::task::JoinError),
+    #[error("The flags {0} and {1} cannot be used together: {2}")]
+    ConflictingFlags(&'static str, &'static str, &'static str),
+    #[error("The install was interrupted")]
+    Interrupted,
+    #[error("{} packages failed to install:{}", .0.len(), describe_failures(.0))]
+    PackagesFailed(Vec<(String, Error)>),
+    #[error("The install was aborted because it took longer than --timeout-overall {0}s")]
+    TimedOut(u64),
+}
+
+impl Error {
+    /// Whether retrying the request that caused the error might succeed
+    fn is_transient(&self) -> bool {
+        match self {
+            Error::HttpStatus { status, .. } => *status >= 500 || *status == 429,
+            Error::TruncatedDownload { .. } => true,
+            // Other than bad requests these are network errors like timeouts or dropped
+            // connections
+            Error::Network(_, e) => !e.is_builder() && !e.is_redirect(),
+            _ => false,
+        }
+    }
+
+    /// A stable name for the kind of error, for scripts to match on rather than the message
+    ///
+    /// Codes are never changed or reused once published, only added.
+    fn code(&self) -> &'static str {
+        match self {
+            Error::NoCargoToml => "no-manifest",
+            Error::Network(..) => "network",
+            Error::HttpStatus { .. } => "http-status",
+            Error::EmptyResponse { .. } => "empty-response",
+            Error::TruncatedDownload { .. } => "truncated-download",
+            Error::TooManyRedirects(_) => "too-many-redirects",
+            Error::MalformedFeed(..) => "malformed-feed",
+            Error::UndatedSource(_) => "undated-source",
+            Error::MissingLocalSource(_) => "missing-local-source",
+            Error::MalformedManifest(_) => "malformed-manifest",
+            Error::StdinManifest => "stdin-manifest",
+            Error::DownloadTooLarge(..) => "download-too-large",
+            Error::InvalidPattern(_) => "invalid-pattern",
+            Error::MalformedLockfile(_) => "malformed-lockfile",
+            Error::MalformedSnapshot(_) => "malformed-snapshot",
+            Error::NotInSnapshot(..) => "not-in-snapshot",
+            Error::MalformedVendorManifest(_) => "malformed-vendor-manifest",
+            Error::NoVendorManifest(_) => "no-vendor-manifest",
+            Error::NotVendored(_) => "not-vendored",
+            Error::VendorOutdated(..) => "vendor-outdated",
+            Error::VendoredFileChanged(_) => "vendored-file-changed",
+            Error::ExtractionMismatch { .. } => "extraction-mismatch",
+            Error::FrameworkMismatch { .. } => "framework-mismatch",
+            Error::HashMismatch { .. } => "hash-mismatch",
+            Error::NewerClientRequired { .. } => "newer-client-required",
+            Error::InvalidPackage(..) => "invalid-package",
+            Error::Zip(..) => "zip",
+            Error::CorruptEntry { .. } => "corrupt-entry",
+            Error::ExtractFailed { .. } => "extract-failed",
+            Error::PackageMismatch { .. } => "package-mismatch",
+            Error::UnexpectedAuthors { .. } => "unexpected-authors",
+            Error::LicenseNotAllowed { .. } => "license-not-allowed",
+            Error::InvalidPackageId(_) => "invalid-package-id",
+            Error::PackageNotFound(_) | Error::PackageNotFoundSuggestions { .. } => "not-found",
+            Error::UnsafePackageDir(_) => "unsafe-package-dir",
+            Error::NoLockedHash(..) => "no-locked-hash",
+            Error::InvalidProxy(_) => "invalid-proxy",
+            Error::CredentialHelper { .. } => "credential-helper",
+            Error::MissingEnvVar(_) => "missing-env-var",
+            Error::InsecureHttp(_) => "insecure-http",
+            Error::MissingWorkspaceDependency(_) => "missing-workspace-dependency",
+            Error::UnknownLockedSource(..) => "unknown-locked-source",
+            Error::LockedSourceMismatch(..) => "locked-source-mismatch",
+            Error::MissingFramework { .. } => "missing-framework",
+            Error::DependencyExists(_) => "dependency-exists",
+            Error::NoSuchDependency(_) => "no-such-dependency",
+            Error::NoMatchingVersion(..) => "no-matching-version",
+            Error::DeniedWarnings(_) => "denied-warnings",
+            Error::TempDirNotWritable(..) => "temp-dir-not-writable",
+            Error::NoOutDir => "no-out-dir",
+            Error::InvalidHeader(_) => "invalid-header",
+            Error::UnknownResourceType(_) => "unknown-resource-type",
+            Error::ReadFailed(..) => "io",
+            Error::WriteFailed(..) => "io",
+            Error::NoCacheDir => "no-cache-dir",
+            Error::SerializeLockfile(_) => "serialize-lockfile",
+            Error::Serialize(..) => "serialize",
+            Error::Client(_) | Error::Tls(_) => "client",
+            Error::TlsHandshake(..) => "tls-handshake",
+            Error::Runtime(_) => "runtime",
+            Error::NestedRuntime => "nested-runtime",
+            Error::Task(_) => "task",
+            Error::ConflictingFlags(..) => "conflicting-flags",
+            Error::Interrupted => "interrupted",
+            Error::PackagesFailed(_) => "packages-failed",
+            Error::TimedOut(_) => "timed-out",
+        }
+    }
 }
 
 #[derive(Debug, StructOpt)]
-pub struct Install {}
+pub struct Install {
+    /// Path to the Cargo.toml to read dependencies from, or `-` to read it from stdin
+    #[structopt(long, default_value = "Cargo.toml", parse(try_from_os_str = expand_path))]
+    manifest_path: PathBuf,
+    /// The directory to install packages into (defaults to target/nuget)
+    #[structopt(long, parse(try_from_os_str = expand_path))]
+    out_dir: Option<PathBuf>,
+    /// Install packages into a `nuget` folder of the OUT_DIR cargo gives build scripts, so
+    /// they're kept with the build script's other output and cleaned along with it
+    #[structopt(long)]
+    use_out_dir: bool,
+    /// Install packages into this directory, meant to be committed, and record what's in it
+    /// so later installs can use it with --vendored
+    #[structopt(long, parse(try_from_os_str = expand_path))]
+    vendor: Option<PathBuf>,
+    /// Use the packages vendored into this directory by --vendor instead of downloading
+    /// anything, after checking they match nuget.lock and weren't changed
+    #[structopt(long, parse(try_from_os_str = expand_path))]
+    vendored: Option<PathBuf>,
+    /// Download packages and report what would be installed without writing anything
+    #[structopt(long)]
+    dry_run: bool,
+    /// Like --dry-run, but print which files would be added, removed or changed compared to
+    /// what's installed
+    #[structopt(long)]
+    diff: bool,
+    /// Resolve versions and download packages to record their hashes in nuget.lock, like
+    /// `cargo update`, without installing any files
+    #[structopt(long)]
+    update_lock_only: bool,
+    /// Download every package pinned in nuget.lock and check it against the recorded hash,
+    /// bypassing the cache and without installing any files
+    #[structopt(long)]
+    verify_only: bool,
+    /// Overwrite existing files, including dlls already linked into the target profile directories
+    #[structopt(long)]
+    force: bool,
+    /// The NuGet feed to download packages from instead of the sources configured in the
+    /// manifest (v3 feeds are given by their index.json). Defaults to nuget.org.
+    #[structopt(long)]
+    source: Option<String>,
+    /// The api key used to authenticate with sources that don't configure one (defaults to
+    /// GITHUB_TOKEN for GitHub Packages)
+    #[structopt(long)]
+    api_key: Option<String>,
+    /// A command run by the shell to get a token for sources without an api key, which reads
+    /// the source's url from stdin and prints the token to stdout
+    #[structopt(long, env = "CARGO_NUGET_CREDENTIAL_HELPER")]
+    credential_helper: Option<String>,
+    /// An extra `Name: Value` header sent with every request to the sources
+    #[structopt(long, number_of_values = 1, parse(try_from_str = source::parse_header))]
+    header: Vec<(HeaderName, HeaderValue)>,
+    /// Allow sources and package urls that use plain http instead of https
+    #[structopt(long)]
+    allow_insecure_http: bool,
+    /// Abort downloading any package larger than this many bytes
+    #[structopt(long)]
+    max_download_size: Option<u64>,
+    /// Limit the combined download rate of all packages to this many bytes per second
+    #[structopt(long)]
+    max_rate: Option<std::num::NonZeroU64>,
+    /// How many times a download is retried after a network error or server error response,
+    /// at most 10
+    #[structopt(long, default_value = "2", parse(try_from_str = parse_retries))]
+    retries: u32,
+    /// The maximum number of retries across all downloads, so an outage fails the install
+    /// quickly
+    #[structopt(long)]
+    retry_budget: Option<usize>,
+    /// Abort the install if it takes longer than this many seconds in total, no matter how
+    /// many packages or retries are involved
+    #[structopt(long)]
+    timeout_overall: Option<u64>,
+    /// Don't install the dependency with this name
+    #[structopt(long, number_of_values = 1)]
+    exclude_package: Vec<String>,
+    /// Fail the install if a package's license isn't one of these comma separated SPDX
+    /// license ids, e.g. `MIT,Apache-2.0`
+    #[structopt(long, use_delimiter = true)]
+    allowed_licenses: Vec<String>,
+    /// Skip extracting files whose path in the package matches this glob
+    #[structopt(long, number_of_values = 1, parse(try_from_str = Pattern::new))]
+    exclude: Vec<Pattern>,
+    /// A folder inside packages to search for winmds, in order of precedence (defaults to
+    /// lib/uap10.0, build/native, buildTransitive/native, build, buildTransitive,
+    /// contentFiles/any/uap10.0 and content)
+    #[structopt(long, number_of_values = 1)]
+    winmd_folder: Vec<String>,
+    /// Extract the winmds of this target framework (e.g. uap10.0) from lib/<framework> into
+    /// a folder of the same name. Can be given several times to extract several frameworks.
+    /// A glob such as uap10.0* picks the highest version among the matching frameworks.
+    #[structopt(long, number_of_values = 1)]
+    framework: Vec<String>,
+    /// Fail instead of warning when a package has no winmds for a requested framework or
+    /// requires a newer NuGet client than is supported
+    #[structopt(long)]
+    strict: bool,
+    /// Only download packages from the source nuget.lock recorded for them
+    #[structopt(long)]
+    locked: bool,
+    /// Resolve floating versions to the highest match published on or before this date
+    /// (e.g. 2020-06-30 or 2020-06-30T12:00:00Z, in UTC), as they would have been back then
+    #[structopt(long, parse(try_from_str = parse_as_of))]
+    as_of: Option<String>,
+    /// Resolve floating versions to the lowest matching version instead of the highest, to
+    /// test that the declared minimums work. Exact versions are unaffected.
+    #[structopt(long)]
+    prefer_lowest: bool,
+    /// Resolve floating versions against the published versions saved to this file by
+    /// export-snapshot instead of asking the sources
+    #[structopt(long, parse(try_from_os_str = expand_path))]
+    registration_snapshot: Option<PathBuf>,
+    /// Only install dependencies whose version differs from nuget.lock, leaving the others as
+    /// they are
+    #[structopt(long)]
+    only_changed: bool,
+    /// Fail if the winmds extracted from a package differ from those recorded in nuget.lock
+    #[structopt(long)]
+    verify_extraction: bool,
+    /// Only warn instead of failing when a package's hash differs from the one in nuget.lock.
+    /// The package's integrity isn't verified then!
+    #[structopt(long)]
+    ignore_hash: bool,
+    /// The directory downloaded packages are cached in
+    #[structopt(long, parse(try_from_os_str = expand_path))]
+    cache_dir: Option<PathBuf>,
+    /// The directory downloads are streamed into before they're extracted (defaults to the
+    /// system's temporary directory)
+    #[structopt(long, env = "TMPDIR", parse(try_from_os_str = expand_path))]
+    temp_dir: Option<PathBuf>,
+    /// Use cached packages without contacting the source, only downloading packages that
+    /// aren't cached. Packages republished with different contents won't be noticed.
+    #[structopt(long)]
+    prefer_cache: bool,
+    /// Fetch the service index of v3 feeds and the lists of published versions again even if
+    /// a recently cached copy exists
+    #[structopt(long)]
+    refresh_index: bool,
+    /// Give extracted files the modification time recorded in the package instead of the
+    /// current time
+    #[structopt(long)]
+    preserve_mtime: bool,
+    /// Also download each package's symbol package (.snupkg) and extract its PDBs into a
+    /// `symbols` folder. Packages without symbols only cause a warning.
+    #[structopt(long)]
+    symbols: bool,
+    /// Also extract the xml documentation files found in the same folders as winmds
+    #[structopt(long)]
+    include_docs: bool,
+    /// Write each package's .nupkg into its directory as it was downloaded instead of
+    /// extracting any files from it
+    #[structopt(long)]
+    no_extract: bool,
+    /// Skip extracting files larger than this many bytes, with a warning
+    #[structopt(long)]
+    max_file_size: Option<u64>,
+    /// Skip extracting files smaller than this many bytes, with a warning
+    #[structopt(long)]
+    min_file_size: Option<u64>,
+    /// Extract the file a symlink inside a package points to instead of skipping the symlink
+    /// with a warning. Symlinks pointing outside of the package are always skipped.
+    #[structopt(long)]
+    resolve_symlinks: bool,
+    /// Also copy the whole runtimes folder of every package into its output directory,
+    /// keeping its structure, for build steps that pick the runtime identifier themselves
+    #[structopt(long)]
+    include_native_runtimes: bool,
+    /// Also copy the tools folder of every package into its output directory, keeping its
+    /// structure, for packages bundling helper executables
+    #[structopt(long)]
+    include_tools: bool,
+    /// Skip the files of a package that can't be extracted, with a warning, instead of
+    /// failing the install
+    #[structopt(long, alias = "skip-corrupt-entries")]
+    continue_on_extract_error: bool,
+    /// Keep extracted files once in this content addressed directory and fill the output
+    /// directory with links to them
+    #[structopt(long, parse(try_from_os_str = expand_path))]
+    store: Option<PathBuf>,
+    /// What to do with winmds identical to one another package installed: `copy` them,
+    /// `skip-duplicates` or `hardlink` them to the first copy
+    #[structopt(long, default_value = "copy", possible_values = Dedupe::VARIANTS)]
+    dedupe: Dedupe,
+    /// Make the installed files read-only, marking them as generated files not to be edited
+    #[structopt(long)]
+    read_only: bool,
+    /// The maximum number of packages downloaded and extracted at the same time. 0 uses the
+    /// available parallelism of the machine and 1 installs packages one after another.
+    #[structopt(short, long, default_value = "0")]
+    jobs: usize,
+    /// Start at --jobs but halve the number of packages worked on at the same time whenever
+    /// a source answers 429 or 503, and raise it again while downloads succeed. Implied by
+    /// --min-jobs and --max-jobs.
+    #[structopt(long)]
+    adaptive_jobs: bool,
+    /// The number of packages adaptive jobs never go below (defaults to 1)
+    #[structopt(long)]
+    min_jobs: Option<usize>,
+    /// The number of packages adaptive jobs never go above (defaults to --jobs)
+    #[structopt(long)]
+    max_jobs: Option<usize>,
+    /// Print the absolute path of every installed winmd to stdout, one per line and quoted
+    /// where needed so they can be passed to xargs or a shell
+    #[structopt(long)]
+    print_paths: bool,
+    /// Write a Makefile style depfile to this path, listing the installed winmds as outputs
+    /// of Cargo.toml and the lock file, as `rustc --emit=dep-info` does
+    #[structopt(long, parse(try_from_os_str = expand_path))]
+    depfile: Option<PathBuf>,
+    /// Write the absolute paths of every installed winmd to this file, sorted and without
+    /// duplicates, for windows-bindgen or the C#/C++ projection tools
+    #[structopt(long, parse(try_from_os_str = expand_path))]
+    emit_winmd_list: Option<PathBuf>,
+    /// What separates the paths written by --emit-winmd-list
+    #[structopt(long, default_value = "newline", possible_values = &["newline", "comma"])]
+    winmd_list_separator: String,
+    /// Write a JSON report of the install to this path, with the resolved version, source
+    /// and extracted files of every package
+    #[structopt(long, parse(try_from_os_str = expand_path))]
+    report: Option<PathBuf>,
+    /// Write a CycloneDX JSON software bill of materials to this path, with the version,
+    /// source, license and hash of every installed package
+    #[structopt(long, parse(try_from_os_str = expand_path))]
+    sbom: Option<PathBuf>,
+    /// How progress is reported: `human` or `json-lines` for one JSON event per line
+    #[structopt(long, default_value = "human", possible_values = OutputFormat::VARIANTS)]
+    output: OutputFormat,
+    /// Fail if anything is warned about, before any files are written
+    #[structopt(long)]
+    deny_warnings: bool,
+    /// Always show a progress line, even when stderr isn't a terminal or in CI
+    #[structopt(long)]
+    progress: bool,
+    /// Never show a progress line
+    #[structopt(long)]
+    no_progress: bool,
+    /// Stop downloading and extracting packages as soon as one fails (the default)
+    #[structopt(long)]
+    fail_fast: bool,
+    /// Attempt to download and extract every package even if some fail, and report all
+    /// failures at the end
+    #[structopt(long)]
+    no_fail_fast: bool,
+    /// The user agent sent with every request
+    #[structopt(long, env = "CARGO_NUGET_USER_AGENT", default_value = DEFAULT_USER_AGENT)]
+    user_agent: String,
+    /// The oldest TLS version sources may use, connections to feeds that only offer older
+    /// versions fail
+    #[structopt(long, default_value = "1.2", possible_values = &["1.0", "1.1", "1.2"])]
+    min_tls: String,
+    /// The packages passed to `install` as `name@version`, installed instead of the
+    /// dependencies of the manifest
+    #[structopt(skip)]
+    packages: Vec<(String, String)>,
+    /// Take packages from the nupkgs `--no-extract` kept in the output directory before
+    /// the cache and sources, for `reinstall`
+    #[structopt(skip)]
+    reuse_nupkgs: bool,
+}
+
+const DEFAULT_USER_AGENT: &str = concat!("cargo-nuget/", env!("CARGO_PKG_VERSION"));
 
 impl Install {
-    fn perform(&self) -> Result<(), Error> {
-        let bytes = std::fs::read("Cargo.toml").map_err(|_| Error::NoCargoToml)?;
-        let manifest = Manifest::from_slice(&bytes).map_err(|_| Error::MalformedManifest)?;
-        let deps = get_deps(manifest)?;
-        let downloaded_deps = download_dependencies(deps)?;
+    fn perform(&mut self) -> Result<(), Error> {
+        let result = self.install();
+        match &result {
+            // Errors of single packages were already reported along with the package
+            Err(_) if events::package_failed() => {}
+            Err(e) => self.events().emit(Event::Error {
+                package: None,
+                code: e.code(),
+                message: e.to_string(),
+            }),
+            Ok(()) => {}
+        }
+        result
+    }
+
+    fn events(&self) -> Events {
+        Events::new(self.output)
+    }
+
+    /// Whether to draw a progress line while packages are installed
+    fn show_progress(&self) -> bool {
+        if self.progress || self.no_progress {
+            return self.progress;
+        }
+        // The progress line would get in the way of the machine readable output
+        self.output == OutputFormat::Human && Progress::is_wanted()
+    }
+
+    fn install(&mut self) -> Result<(), Error> {
+        self.validate()?;
+        // A diff is a dry run that compares rather than lists the files
+        if self.diff {
+            self.dry_run = true;
+        }
+        // Only the versions nuget.lock pins have a hash to verify
+        if self.verify_only {
+            self.locked = true;
+        }
+        let started = SystemTime::now();
+        let deadline = self.timeout_overall.map(Deadline::after);
+        let bytes = self.read_manifest()?;
+        let manifest =
+            Manifest::from_slice(&bytes).map_err(|e| Error::MalformedManifest(e.to_string()))?;
+        let metadata = manifest.package.and_then(|p| p.metadata);
+        let config = get_config(metadata.as_ref())?;
+        self.apply_defaults(&config);
+        let mut deps = get_deps(metadata.as_ref(), || self.workspace_metadata(&bytes))?;
+        if !self.packages.is_empty() {
+            deps = self.override_deps(deps)?;
+        }
+        let declared: Vec<String> = deps.iter().map(|d| d.name.clone()).collect();
+        for name in &self.exclude_package {
+            if !declared.iter().any(|d| d.eq_ignore_ascii_case(name)) {
+                warn(format!(
+                    "{} was passed to --exclude-package but is not a dependency",
+                    name
+                ));
+            }
+        }
+        deps.retain(|d| {
+            !self
+                .exclude_package
+                .iter()
+                .any(|n| n.eq_ignore_ascii_case(&d.name))
+        });
+        for dep in &mut deps {
+            dep.exclude.extend(self.exclude.iter().cloned());
+            if let Some(url) = &dep.url {
+                self.check_insecure_http(url)?;
+            }
+        }
+        let lock_path = self.workspace_root().join(lock::LOCK_FILE_NAME);
+        let mut lockfile = Lockfile::load(&lock_path)?.unwrap_or_default();
+        let out_dir = self.out_dir()?;
+        if let Some(dir) = &self.vendored {
+            self.verify_vendored(dir, &deps, &lockfile)?;
+            let wanted: Vec<String> = deps.iter().map(|d| d.name.clone()).collect();
+            return self.write_paths(&out_dir, &lockfile, &wanted, &lock_path);
+        }
+        let mut downloader = self.downloader(config)?;
+        if self.verify_only {
+            // The packages have to come from the sources for tampering to be noticed
+            downloader.cache = None;
+            downloader.symbols = false;
+        }
+        if self.no_extract {
+            downloader.symbols = false;
+        }
+        // Don't download anything if e.g. a source was already warned about
+        self.check_warnings()?;
+        let mut runtime = new_runtime()?;
+        // Keep listening for Ctrl-C in the background once the downloads are done
+        runtime.spawn(ctrl_c());
+        for dep in deps.iter_mut().filter(|d| d.url.is_none()) {
+            let floating = match FloatingVersion::parse(&dep.version) {
+                Some(f) => f,
+                None => continue,
+            };
+            let locked = lockfile.packages.iter().find(|p| p.name == dep.name);
+            dep.version = match locked {
+                // The locked version is kept as long as it still matches
+                Some(locked) if self.locked && floating.matches(&locked.version) => {
+                    locked.version.clone()
+                }
+                _ => {
+                    let locked_source = locked
+                        .and_then(|l| l.source.as_deref())
+                        .filter(|_| self.locked);
+                    runtime.block_on(within(
+                        deadline,
+                        resolve_floating(&downloader, &dep.name, &dep.version, locked_source),
+                    ))?
+                }
+            };
+        }
+        for dep in deps.iter_mut().filter(|d| d.url.is_none()) {
+            let locked = lockfile.find(&dep.name, &dep.version);
+            dep.locked_sha256 = locked.and_then(|p| p.sha256.clone());
+            if self.locked {
+                dep.locked_source = locked.and_then(|p| p.source.clone());
+            }
+        }
+        let wanted: Vec<String> = deps.iter().map(|d| d.name.clone()).collect();
+        if self.only_changed {
+            // A locked version is only trusted if it's still installed
+            deps.retain(|d| {
+                lockfile.find(&d.name, &d.version).is_none() || !out_dir.join(&d.name).exists()
+            });
+        }
+        let options = self.extract_options();
+        let progress = Some(Arc::new(Progress::new(deps.len()))).filter(|_| self.show_progress());
+        let downloaded_deps = download_dependencies(
+            &mut runtime,
+            deps,
+            &downloader,
+            &options,
+            progress.as_ref(),
+            deadline,
+            !self.no_fail_fast,
+        );
+        if let Some(progress) = &progress {
+            progress.finish();
+        }
+        let mut downloaded_deps = downloaded_deps?;
+        // Install and report packages in a stable order no matter how they were declared
+        downloaded_deps.sort_by(|a, b| a.dependency.name.cmp(&b.dependency.name));
+        if self.verify_only {
+            return verify_downloads(&downloaded_deps, &lockfile);
+        }
+        for dep in &downloaded_deps {
+            dep.verify_hash(&lockfile, self.ignore_hash)?;
+        }
+        if !self.allowed_licenses.is_empty() {
+            check_licenses(
+                &downloaded_deps,
+                &LicensePolicy::new(self.allowed_licenses.clone()),
+            )?;
+        }
+        if self.verify_extraction {
+            for dep in &downloaded_deps {
+                dep.verify_against(&lockfile)?;
+            }
+        }
+
+        let store = match &self.store {
+            Some(dir) if self.writes_files() => Some(Store::new(dir.clone())?),
+            _ => None,
+        };
+        let store = store.as_ref();
+        let mut installed = Vec::new();
+        let mut reports = Vec::new();
+        let mut components = Vec::new();
+        let mut vendored = Vec::new();
+        if self.writes_files() {
+            let needed = downloaded_deps.iter().map(|d| d.extracted_size()).sum();
+            warn_if_low_on_space(&out_dir, needed);
+        }
+        // Nothing has been written yet so failing here leaves the previous install intact
+        self.check_warnings()?;
+        let events = self.events();
+        let package_count = downloaded_deps.len();
+        let mut deduper = Deduper::new(self.dedupe);
+        let mut changes = Changes::default();
         for dep in downloaded_deps {
-            let dep_directory = workspace_root()
-                .join("target")
-                .join("nuget")
-                .join(&dep.dependency.name);
-            // create the dependency directory
-            std::fs::create_dir_all(&dep_directory).unwrap();
-            for winmd in dep.winmds() {
-                winmd.write(&dep_directory).unwrap();
+            check_interrupted()?;
+            if let Some(deadline) = deadline {
+                deadline.check()?;
+            }
+            let mut locked = dep.locked();
+            if self.ignore_hash {
+                // Keep the hash of the package that was locked so the mismatch isn't forgotten
+                if let Some(sha256) = lockfile
+                    .find(&locked.name, &locked.version)
+                    .and_then(|p| p.sha256.clone())
+                {
+                    locked.sha256 = Some(sha256);
+                }
+            }
+            if dep.nupkg.is_some() {
+                // Nothing was extracted, so keep what an earlier install extracted to verify
+                // against
+                if let Some(previous) = lockfile.find(&locked.name, &locked.version) {
+                    locked.winmds = previous.winmds.clone();
+                    locked.frameworks = previous.frameworks.clone();
+                }
+            }
+            if locked.source.is_none() {
+                // Packages taken from the cache came from wherever they were locked to before
+                locked.source = lockfile
+                    .find(&locked.name, &locked.version)
+                    .and_then(|p| p.source.clone());
+            }
+            let report = PackageReport {
+                source: locked.source.clone(),
+                ..dep.report()
+            };
+            if self.vendor.is_some() {
+                vendored.push(VendoredPackage {
+                    name: locked.name.clone(),
+                    version: locked.version.clone(),
+                    sha256: dep.sha256.clone(),
+                    files: report.files.clone(),
+                });
+            }
+            reports.push(report);
+            components.push(Component::new(
+                &locked.name,
+                &locked.version,
+                &dep.sha256,
+                dep.license.as_ref(),
+                locked.source.as_deref(),
+            ));
+            installed.push(locked);
+            if self.update_lock_only {
+                continue;
+            }
+            let dep_directory = package_dir(&out_dir, &dep.dependency.name)?;
+            if self.diff {
+                changes.compare(&dep.dependency.name, &dep_directory, &dep.report().files)?;
+                continue;
+            }
+            if self.dry_run {
+                for winmd in dep.winmds() {
+                    println!("{}", dep_directory.join(&winmd.name).display());
+                }
+                for dll in dep.dlls() {
+                    println!("{}", dep_directory.join(&dll.name).display());
+                }
+                for doc in dep.docs() {
+                    println!("{}", dep_directory.join(&doc.name).display());
+                }
+                for pdb in dep.symbols() {
+                    println!("{}", pdb.path(&dep_directory).display());
+                }
+                for file in dep.runtimes().iter().chain(dep.tools()) {
+                    println!("{}", dep_directory.join(&file.name).display());
+                }
+                if dep.nupkg.is_some() {
+                    println!("{}", dep_directory.join(dep.nupkg_name()).display());
+                }
+                continue;
+            }
+            let written = write_atomically(&dep_directory, |dir| {
+                for winmd in dep.winmds() {
+                    deduper.write(winmd, dir, &dep_directory, store)?;
+                }
+                for dll in dep.dlls() {
+                    dll.write(dir, store)?;
+                }
+                for doc in dep.docs() {
+                    doc.write(dir, store)?;
+                }
+                for pdb in dep.symbols() {
+                    pdb.write(dir, store)?;
+                }
+                for file in dep.runtimes().iter().chain(dep.tools()) {
+                    file.write(dir, store)?;
+                }
+                if let Some(nupkg) = &dep.nupkg {
+                    let path = dir.join(dep.nupkg_name());
+                    std::fs::write(&path, nupkg).map_err(write_error(&path))?;
+                }
+                if self.read_only {
+                    set_read_only(dir, true).map_err(write_error(dir))?;
+                }
+                // Throw away the new files rather than replacing the previous install
+                // once the user asked to stop
+                check_interrupted()
+            });
+            if let Err(e) = written {
+                events.emit(Event::Error {
+                    package: Some(&dep.dependency.name),
+                    code: e.code(),
+                    message: e.to_string(),
+                });
+                return Err(e);
             }
+            events.emit(Event::Extracted {
+                package: &dep.dependency.name,
+                version: &dep.dependency.version,
+                winmds: dep.winmds().len(),
+                dlls: dep.dlls().len(),
+                docs: dep.docs().len(),
+                symbols: dep.symbols().len(),
+                runtimes: dep.runtimes().len(),
+                tools: dep.tools().len(),
+            });
 
             for dll in dep.dlls() {
-                dll.write(&dep_directory).unwrap();
+                dll.link(&self.workspace_root(), &dep_directory, self.force)?;
             }
         }
 
+        // The lock file records what the manifest asks for, not a one-off experiment
+        if !self.dry_run && self.packages.is_empty() {
+            lockfile.update(installed, &declared);
+            lockfile.save(&lock_path)?;
+            if let Some(dir) = &self.vendor {
+                let mut manifest = VendorManifest::load(dir)?.unwrap_or_default();
+                manifest.update(vendored, &declared);
+                manifest.save(dir)?;
+            }
+        }
+        if self.update_lock_only {
+            eprintln!(
+                "Updated {} without installing any files",
+                lock_path.display()
+            );
+        }
+        deduper.print_summary();
+        if self.diff {
+            changes.print_summary();
+        }
+        events.emit(Event::Finished {
+            packages: package_count,
+        });
+        self.write_paths(&out_dir, &lockfile, &wanted, &lock_path)?;
+        if let Some(report) = &self.report {
+            let environment = Environment {
+                tool_version: env!("CARGO_PKG_VERSION"),
+                sources: downloader
+                    .sources
+                    .iter()
+                    .map(|s| s.recorded_url().to_owned())
+                    .collect(),
+                cache: downloader.cache.is_some(),
+                prefer_cache: downloader.prefer_cache,
+                locked: self.locked,
+                manifest_sha256: format!("{:x}", Sha256::digest(&bytes)),
+            };
+            Report::new(started, environment, reports).save(report)?;
+        }
+        if let Some(sbom) = &self.sbom {
+            Sbom::new(components).save(sbom)?;
+        }
         Ok(())
     }
-}
 
-fn workspace_root() -> PathBuf {
-    // TODO: improve this
-    PathBuf::new()
-}
+    /// Checks that every dependency is vendored in `dir` as nuget.lock records it, see
+    /// `--vendored`
+    fn verify_vendored(
+        &self,
+        dir: &Path,
+        deps: &[Dependency],
+        lockfile: &Lockfile,
+    ) -> Result<(), Error> {
+        let manifest =
+            VendorManifest::load(dir)?.ok_or_else(|| Error::NoVendorManifest(dir.to_owned()))?;
+        for dep in deps {
+            let locked = lockfile
+                .packages
+                .iter()
+                .find(|p| p.name == dep.name)
+                .ok_or_else(|| Error::NotVendored(dep.name.clone()))?;
+            // Without downloading anything floating versions can only be checked against
+            // the lock file
+            let wanted = match FloatingVersion::parse(&dep.version) {
+                Some(floating) => floating.matches(&locked.version),
+                None => dep.url.is_some() || dep.version == locked.version,
+            };
+            if !wanted {
+                return Err(Error::VendorOutdated(
+                    dep.name.clone(),
+                    locked.version.clone(),
+                ));
+            }
+            manifest.verify(dir, &dep.name, lockfile)?;
+        }
+        Ok(())
+    }
 
-fn get_deps(manifest: Manifest) -> Result<Vec<Dependency>, Error> {
-    let metadata = manifest.package.and_then(|p| p.metadata);
-    match metadata {
-        Some(Value::Table(mut t)) => {
-            let deps = match t.remove("nuget_dependencies") {
-                Some(Value::Table(deps)) => deps,
-                _ => return Err(Error::MalformedManifest.into()),
+    /// Prints or writes the paths of the installed winmds of the `wanted` dependencies as
+    /// requested by --print-paths, --depfile and --emit-winmd-list
+    fn write_paths(
+        &self,
+        out_dir: &Path,
+        lockfile: &Lockfile,
+        wanted: &[String],
+        lock_path: &Path,
+    ) -> Result<(), Error> {
+        let absolute = |path: &Path| {
+            std::path::absolute(path).map_err(|e| Error::ReadFailed(path.to_owned(), e))
+        };
+        let out_dir = absolute(out_dir)?;
+        // Packages skipped by --only-changed are still installed so the lock file
+        // knows the winmds of every wanted package
+        let winmds: Vec<PathBuf> = lockfile
+            .packages
+            .iter()
+            .filter(|p| wanted.contains(&p.name))
+            .flat_map(|p| {
+                let dir = out_dir.join(&p.name);
+                p.winmds.iter().map(move |w| dir.join(w))
+            })
+            // Duplicates left out by --dedupe skip-duplicates are installed by another package
+            .filter(|w| self.dedupe != Dedupe::SkipDuplicates || w.exists())
+            .collect();
+        if self.print_paths {
+            for winmd in &winmds {
+                println!("{}", shell_quote(winmd));
+            }
+        }
+        if let Some(depfile) = &self.depfile {
+            let mut inputs = vec![absolute(lock_path)?];
+            if !self.reads_stdin() {
+                inputs.insert(0, absolute(&self.manifest_path)?);
+            }
+            write_depfile(depfile, &winmds, &inputs)?;
+        }
+        if let Some(path) = &self.emit_winmd_list {
+            let separator = if self.winmd_list_separator == "comma" {
+                ","
+            } else {
+                "\n"
             };
-            deps.into_iter()
-                .map(|(key, value)| match value {
-                    Value::String(version) => Ok(Dependency::new(key, version)),
-                    _ => Err(Error::MalformedManifest.into()),
-                })
-                .collect()
+            write_winmd_list(path, &winmds, separator)?;
         }
-        _ => return Err(Error::MalformedManifest.into()),
+        Ok(())
     }
-}
 
-#[derive(Debug)]
-struct Dependency {
-    name: String,
-    version: String,
-}
+    /// Checks for combinations of flags that contradict each other
+    fn validate(&self) -> Result<(), Error> {
+        let packages = !self.packages.is_empty();
+        let conflicts = [
+            (
+                ("--no-extract", self.no_extract),
+                ("--verify-extraction", self.verify_extraction),
+                "nothing is extracted to verify",
+            ),
+            (
+                ("--no-extract", self.no_extract),
+                ("--symbols", self.symbols),
+                "symbols are extracted from their own package",
+            ),
+            (
+                ("--no-extract", self.no_extract),
+                ("--include-docs", self.include_docs),
+                "nothing is extracted from the package",
+            ),
+            (
+                ("--no-extract", self.no_extract),
+                ("--include-native-runtimes", self.include_native_runtimes),
+                "nothing is extracted from the package",
+            ),
+            (
+                ("--no-extract", self.no_extract),
+                ("--include-tools", self.include_tools),
+                "nothing is extracted from the package",
+            ),
+            (
+                ("PACKAGE@VERSION", packages),
+                ("--update-lock-only", self.update_lock_only),
+                "packages installed by name never update the lock file",
+            ),
+            (
+                ("PACKAGE@VERSION", packages),
+                ("--verify-only", self.verify_only),
+                "only the versions the lock file pins can be verified",
+            ),
+            (
+                ("PACKAGE@VERSION", packages),
+                ("--vendor", self.vendor.is_some()),
+                "the vendor manifest records what Cargo.toml asks for",
+            ),
+            (
+                ("PACKAGE@VERSION", packages),
+                ("--vendored", self.vendored.is_some()),
+                "only the packages the vendor manifest records can be used",
+            ),
+            (
+                ("--dry-run", self.dry_run),
+                ("--force", self.force),
+                "a dry run never writes files so there is nothing to overwrite",
+            ),
+            (
+                ("--diff", self.diff),
+                ("--dry-run", self.dry_run),
+                "--diff is a dry run already",
+            ),
+            (
+                ("--diff", self.diff),
+                ("--force", self.force),
+                "a diff never writes files so there is nothing to overwrite",
+            ),
+            (
+                ("--diff", self.diff),
+                ("--print-paths", self.print_paths),
+                "a diff already prints the paths it would change",
+            ),
+            (
+                ("--diff", self.diff),
+                ("--update-lock-only", self.update_lock_only),
+                "a diff doesn't update the lock file",
+            ),
+            (
+                ("--diff", self.diff),
+                ("--verify-only", self.verify_only),
+                "neither installs anything",
+            ),
+            (
+                ("--diff", self.diff),
+                ("--depfile", self.depfile.is_some()),
+                "a diff never writes files",
+            ),
+            (
+                ("--diff", self.diff),
+                ("--emit-winmd-list", self.emit_winmd_list.is_some()),
+                "a diff never writes files",
+            ),
+            (
+                ("--diff", self.diff),
+                ("--report", self.report.is_some()),
+                "a diff doesn't extract anything to report on",
+            ),
+            (
+                ("--diff", self.diff),
+                ("--sbom", self.sbom.is_some()),
+                "a diff never writes files",
+            ),
+            (
+                ("--dry-run", self.dry_run),
+                ("--print-paths", self.print_paths),
+                "a dry run already prints the paths it would install",
+            ),
+            (
+                ("--out-dir", self.out_dir.is_some()),
+                ("--use-out-dir", self.use_out_dir),
+                "they both choose the output directory",
+            ),
+            (
+                ("--vendor", self.vendor.is_some()),
+                ("--out-dir", self.out_dir.is_some()),
+                "the vendor directory is the output directory",
+            ),
+            (
+                ("--vendor", self.vendor.is_some()),
+                ("--use-out-dir", self.use_out_dir),
+                "the vendor directory is the output directory",
+            ),
+            (
+                ("--vendor", self.vendor.is_some()),
+                ("--vendored", self.vendored.is_some()),
+                "--vendored never installs anything",
+            ),
+            (
+                ("--vendored", self.vendored.is_some()),
+                ("--out-dir", self.out_dir.is_some()),
+                "the vendored packages are used where they are",
+            ),
+            (
+                ("--vendored", self.vendored.is_some()),
+                ("--use-out-dir", self.use_out_dir),
+                "the vendored packages are used where they are",
+            ),
+            (
+                ("--vendored", self.vendored.is_some()),
+                ("--report", self.report.is_some()),
+                "nothing is installed to report on",
+            ),
+            (
+                ("--vendored", self.vendored.is_some()),
+                ("--sbom", self.sbom.is_some()),
+                "nothing is installed to list",
+            ),
+            (
+                ("--vendored", self.vendored.is_some()),
+                ("--allowed-licenses", !self.allowed_licenses.is_empty()),
+                "the vendored packages aren't read",
+            ),
+            (
+                ("--as-of", self.as_of.is_some()),
+                (
+                    "--registration-snapshot",
+                    self.registration_snapshot.is_some(),
+                ),
+                "the snapshot only lists the versions published when it was exported, pass --as-of to export-snapshot instead",
+            ),
+            (
+                ("--dedupe", self.dedupe != Dedupe::Copy),
+                ("--store", self.store.is_some()),
+                "the store already keeps every file once",
+            ),
+            (
+                ("--read-only", self.read_only),
+                ("--store", self.store.is_some()),
+                "installed files are links to the store, which is shared with other projects",
+            ),
+            (
+                (
+                    "--dedupe skip-duplicates",
+                    self.dedupe == Dedupe::SkipDuplicates,
+                ),
+                ("--vendor", self.vendor.is_some()),
+                "every vendored package has to contain all of its files",
+            ),
+            (
+                ("--progress", self.progress),
+                ("--no-progress", self.no_progress),
+                "they contradict each other",
+            ),
+            (
+                ("--fail-fast", self.fail_fast),
+                ("--no-fail-fast", self.no_fail_fast),
+                "they contradict each other",
+            ),
+            (
+                ("--dry-run", self.dry_run),
+                ("--depfile", self.depfile.is_some()),
+                "a dry run never writes files",
+            ),
+            (
+                ("--dry-run", self.dry_run),
+                ("--emit-winmd-list", self.emit_winmd_list.is_some()),
+                "a dry run never writes files",
+            ),
+            (
+                ("--dry-run", self.dry_run),
+                ("--report", self.report.is_some()),
+                "a dry run doesn't extract anything to report on",
+            ),
+            (
+                ("--dry-run", self.dry_run),
+                ("--sbom", self.sbom.is_some()),
+                "a dry run never writes files",
+            ),
+            (
+                ("--update-lock-only", self.update_lock_only),
+                ("--dry-run", self.dry_run),
+                "a dry run doesn't update the lock file",
+            ),
+            (
+                ("--verify-only", self.verify_only),
+                ("--ignore-hash", self.ignore_hash),
+                "nothing would be verified",
+            ),
+            (
+                ("--verify-only", self.verify_only),
+                ("--prefer-cache", self.prefer_cache),
+                "the packages are always downloaded to be verified",
+            ),
+            (
+                ("--verify-only", self.verify_only),
+                ("--dry-run", self.dry_run),
+                "neither installs anything",
+            ),
+            (
+                ("--verify-only", self.verify_only),
+                ("--update-lock-only", self.update_lock_only),
+                "the lock file is what's verified against",
+            ),
+            (
+                ("--verify-only", self.verify_only),
+                ("--vendor", self.vendor.is_some()),
+                "nothing is installed to vendor",
+            ),
+            (
+                ("--verify-only", self.verify_only),
+                ("--vendored", self.vendored.is_some()),
+                "--vendored already checks the vendored packages",
+            ),
+            (
+                ("--verify-only", self.verify_only),
+                ("--print-paths", self.print_paths),
+                "no winmds are installed",
+            ),
+            (
+                ("--verify-only", self.verify_only),
+                ("--depfile", self.depfile.is_some()),
+                "no winmds are installed",
+            ),
+            (
+                ("--verify-only", self.verify_only),
+                ("--emit-winmd-list", self.emit_winmd_list.is_some()),
+                "no winmds are installed",
+            ),
+            (
+                ("--verify-only", self.verify_only),
+                ("--report", self.report.is_some()),
+                "nothing is installed to report on",
+            ),
+            (
+                ("--verify-only", self.verify_only),
+                ("--sbom", self.sbom.is_some()),
+                "nothing is installed to list",
+            ),
+            (
+                ("--update-lock-only", self.update_lock_only),
+                ("--vendor", self.vendor.is_some()),
+                "nothing is installed to vendor",
+            ),
+            (
+                ("--update-lock-only", self.update_lock_only),
+                ("--print-paths", self.print_paths),
+                "no winmds are installed",
+            ),
+            (
+                ("--update-lock-only", self.update_lock_only),
+                ("--depfile", self.depfile.is_some()),
+                "no winmds are installed",
+            ),
+            (
+                ("--update-lock-only", self.update_lock_only),
+                ("--emit-winmd-list", self.emit_winmd_list.is_some()),
+                "no winmds are installed",
+            ),
+            (
+                ("--update-lock-only", self.update_lock_only),
+                ("--report", self.report.is_some()),
+                "nothing is extracted to report on",
+            ),
+            (
+                ("--framework", !self.framework.is_empty()),
+                ("--winmd-folder", !self.winmd_folder.is_empty()),
+                "each framework decides which folder its winmds are extracted from",
+            ),
+            (
+                ("--print-paths", self.print_paths),
+                (
+                    "--output json-lines",
+                    self.output == OutputFormat::JsonLines,
+                ),
+                "both print to stdout",
+            ),
+            (
+                ("--only-changed", self.only_changed),
+                ("--sbom", self.sbom.is_some()),
+                "the bill of materials would miss the unchanged packages",
+            ),
+            (
+                ("--only-changed", self.only_changed),
+                ("--report", self.report.is_some()),
+                "the report would miss the unchanged packages",
+            ),
+            (
+                ("--dry-run", self.dry_run),
+                (
+                    "--output json-lines",
+                    self.output == OutputFormat::JsonLines,
+                ),
+                "both print to stdout",
+            ),
+            (
+                ("--diff", self.diff),
+                (
+                    "--output json-lines",
+                    self.output == OutputFormat::JsonLines,
+                ),
+                "both print to stdout",
+            ),
+        ];
+        for ((first, first_set), (second, second_set), reason) in conflicts.iter() {
+            if *first_set && *second_set {
+                return Err(Error::ConflictingFlags(first, second, reason));
+            }
+        }
+        Ok(())
+    }
 
-impl Dependency {
-    fn new(name: String, version: String) -> Self {
-        Self { name, version }
+    /// Whether the install writes the packages' files, unlike a dry run or
+    /// `--update-lock-only`
+    fn writes_files(&self) -> bool {
+        !self.dry_run && !self.update_lock_only
     }
 
-    fn url(&self) -> String {
-        format!(
-            "https://www.nuget.org/api/v2/package/{}/{}",
-            self.name, self.version
-        )
+    /// Uses the settings of `[package.metadata.nuget]` for whatever wasn't given on the
+    /// command line
+    ///
+    /// Flags only turn the asset settings on, so they can't be turned off for a single
+    /// install once the manifest turns them on.
+    fn apply_defaults(&mut self, config: &Config) {
+        // Winmd folders are replaced by frameworks as a whole
+        if self.framework.is_empty() && self.winmd_folder.is_empty() {
+            self.framework = config.framework.clone().map_or_else(Vec::new, Vec::from);
+        }
+        let out_dir_given = self.out_dir.is_some()
+            || self.use_out_dir
+            || self.vendor.is_some()
+            || self.vendored.is_some();
+        if !out_dir_given {
+            // Like other paths in Cargo.toml it's relative to the manifest
+            self.out_dir = config
+                .out_dir
+                .as_ref()
+                .map(|dir| self.workspace_root().join(dir));
+        }
+        self.include_docs |= config.include_docs;
+        self.include_native_runtimes |= config.include_native_runtimes;
+        self.include_tools |= config.include_tools;
     }
 
-    async fn download(&self) -> Result<Vec<u8>, Error> {
-        fn try_download(
-            url: String,
-            recursion_amount: u8,
-        ) -> BoxFuture<'static, Result<Vec<u8>, Error>> {
-            async move {
-                if recursion_amount == 0 {
-                    return Err(Error::DownloadError(
-                        anyhow::anyhow!("Too many redirects").into(),
-                    ));
+    /// The requested frameworks without duplicates, which are compared case insensitively
+    fn frameworks(&self) -> Vec<String> {
+        let mut frameworks: Vec<String> = Vec::new();
+        for framework in &self.framework {
+            if !frameworks.iter().any(|f| f.eq_ignore_ascii_case(framework)) {
+                frameworks.push(framework.clone());
+            }
+        }
+        frameworks
+    }
+
+    /// The packages passed as `name@version`, taking every other option of a package from
+    /// its declaration in the manifest if it has one
+    fn override_deps(&self, mut declared: Vec<Dependency>) -> Result<Vec<Dependency>, Error> {
+        let mut deps = Vec::new();
+        for (name, version) in &self.packages {
+            let dep = match declared
+                .iter()
+                .position(|d| d.name.eq_ignore_ascii_case(name))
+            {
+                // The version replaces a url as well, which pins a single version
+                Some(i) => Dependency {
+                    version: version.clone(),
+                    requested: version.clone(),
+                    url: None,
+                    ..declared.swap_remove(i)
+                },
+                None => {
+                    Dependency::from_spec(name.clone(), DependencySpec::Version(version.clone()))?
                 }
-                let res = reqwest::get(&url)
-                    .await
-                    .map_err(|e| Error::DownloadError(e.into()))?;
-                match res.status().into() {
-                    200u16 => {
-                        let bytes = res
-                            .bytes()
-                            .await
-                            .map_err(|e| Error::DownloadError(e.into()))?;
-                        Ok(bytes.into_iter().collect())
-                    }
-                    302 => {
-                        let headers = res.headers();
-                        let redirect_url = headers.get("Location").unwrap();
+            };
+            deps.push(dep);
+        }
+        Ok(deps)
+    }
 
-                        let url = redirect_url.to_str().unwrap();
+    /// Extracts the locked packages again, see `Subcommand::Reinstall`
+    fn reinstall(&mut self) -> Result<(), Error> {
+        if self.update_lock_only {
+            return Err(Error::ConflictingFlags(
+                "reinstall",
+                "--update-lock-only",
+                "reinstalling only writes files",
+            ));
+        }
+        if self.only_changed {
+            return Err(Error::ConflictingFlags(
+                "reinstall",
+                "--only-changed",
+                "reinstalling extracts every package again",
+            ));
+        }
+        // Checked here since the flags forced below would otherwise be reported instead
+        if self.verify_only {
+            return Err(Error::ConflictingFlags(
+                "reinstall",
+                "--verify-only",
+                "reinstalling uses the packages at hand instead of downloading them to verify",
+            ));
+        }
+        // Locked versions don't need the sources to be resolved, and locked packages are
+        // taken from the output directory or the cache, or downloaded from where they came
+        // from otherwise
+        self.locked = true;
+        self.prefer_cache = true;
+        self.reuse_nupkgs = true;
+        self.perform()
+    }
 
-                        try_download(url.to_owned(), recursion_amount - 1).await
-                    }
-                    _ => {
-                        return Err(Error::DownloadError(
-                            anyhow::anyhow!("Non-successful response: {}", res.status()).into(),
-                        ))
-                    }
+    /// Caches the installed packages recorded in the lock file, see `Subcommand::ImportCache`
+    fn import_cache(&mut self) -> Result<(), Error> {
+        let bytes = self.read_manifest()?;
+        let manifest =
+            Manifest::from_slice(&bytes).map_err(|e| Error::MalformedManifest(e.to_string()))?;
+        let metadata = manifest.package.and_then(|p| p.metadata);
+        let config = get_config(metadata.as_ref())?;
+        self.apply_defaults(&config);
+        let deps = get_deps(metadata.as_ref(), || self.workspace_metadata(&bytes))?;
+        let lock_path = self.workspace_root().join(lock::LOCK_FILE_NAME);
+        let lockfile = Lockfile::load(&lock_path)?.unwrap_or_default();
+        let out_dir = self.out_dir()?;
+        let mut downloader = self.downloader(config)?;
+        // Only packages that match the installed files may end up in the cache
+        let cache = downloader.cache.take().ok_or(Error::NoCacheDir)?;
+        downloader.prefer_cache = false;
+        downloader.symbols = false;
+        let options = self.extract_options();
+        let mut runtime = new_runtime()?;
+        let mut imported = 0;
+        for mut dep in deps {
+            let locked = match lockfile.find(&dep.name, &dep.version) {
+                Some(l) => l,
+                None => {
+                    warn(format!(
+                        "skipping {} {} which isn't in the lock file",
+                        dep.name, dep.version
+                    ));
+                    continue;
                 }
+            };
+            let dir = out_dir.join(&dep.name);
+            if !dir.exists() || dep.url.is_some() || cache.get(&dep.name, &dep.version).is_some() {
+                continue;
             }
-            .boxed()
+            dep.exclude.extend(self.exclude.iter().cloned());
+            dep.locked_source = locked.source.clone();
+            dep.locked_sha256 = locked.sha256.clone();
+            let (name, version) = (dep.name.clone(), dep.version.clone());
+            let download = runtime.block_on(dep.download(&downloader))?;
+            let bytes = download.bytes.clone();
+            let validators = download.validators.clone();
+            let package = DownloadedDependency::new(dep, download, None, &options, None)?;
+            let matches = package.locked().winmds == locked.winmds
+                && package
+                    .winmds()
+                    .iter()
+                    .all(|w| std::fs::read(dir.join(&w.name)).ok().as_ref() == Some(&w.contents));
+            if !matches {
+                warn(format!(
+                    "skipping {} {} whose installed winmds differ from the package",
+                    name, version
+                ));
+                continue;
+            }
+            cache
+                .put(&name, &version, &bytes, validators.as_ref())
+                .map_err(|e| Error::WriteFailed(cache.path(&name, &version), e))?;
+            eprintln!("Imported {} {}", name, version);
+            imported += 1;
         }
+        eprintln!("Imported {} package(s) into the cache", imported);
+        self.check_warnings()
+    }
 
-        try_download(self.url(), 5).await
+    /// Saves the published versions of every dependency, see `Subcommand::ExportSnapshot`
+    fn export_snapshot(&self, path: &Path) -> Result<(), Error> {
+        let bytes = self.read_manifest()?;
+        let manifest =
+            Manifest::from_slice(&bytes).map_err(|e| Error::MalformedManifest(e.to_string()))?;
+        let metadata = manifest.package.and_then(|p| p.metadata);
+        let config = get_config(metadata.as_ref())?;
+        let deps = get_deps(metadata.as_ref(), || self.workspace_metadata(&bytes))?;
+        let mut downloader = self.downloader(config)?;
+        // The snapshot is taken from the sources, not from an older snapshot
+        downloader.snapshot = None;
+        let mut runtime = new_runtime()?;
+        let mut snapshot = Snapshot::default();
+        let mut saved = 0;
+        // Packages downloaded from their own url aren't resolved so there's nothing to save
+        for dep in deps.iter().filter(|d| d.url.is_none()) {
+            let published = runtime.block_on(published_versions(&downloader, &dep.name, None))?;
+            snapshot
+                .packages
+                .extend(
+                    published
+                        .into_iter()
+                        .map(|(source, versions)| SnapshotPackage {
+                            name: dep.name.clone(),
+                            source,
+                            versions,
+                        }),
+                );
+            saved += 1;
+        }
+        snapshot.save(path)?;
+        eprintln!(
+            "Saved the published versions of {} package(s) to {}",
+            saved,
+            path.display()
+        );
+        Ok(())
     }
-}
 
-struct DownloadedDependency {
-    dependency: Dependency,
-    contents: (Vec<Winmd>, Vec<Dll>),
-}
+    /// Fails if anything was warned about and `--deny-warnings` was passed
+    fn check_warnings(&self) -> Result<(), Error> {
+        match events::warnings().len() {
+            count if count > 0 && self.deny_warnings => Err(Error::DeniedWarnings(count)),
+            _ => Ok(()),
+        }
+    }
 
-impl DownloadedDependency {
-    fn new(dependency: Dependency, bytes: Vec<u8>) -> Result<Self, Error> {
-        let contents = Self::read_contents(&bytes)?;
-        Ok(Self {
-            dependency,
-            contents,
-        })
+    /// Downloads a single package to be inspected, without a dependency on it
+    fn download_package(&self, name: String, version: String) -> Result<Vec<u8>, Error> {
+        // Packages can be inspected outside of a project using the default sources
+        let config = match self.read_manifest() {
+            Ok(bytes) => {
+                let manifest = Manifest::from_slice(&bytes)
+                    .map_err(|e| Error::MalformedManifest(e.to_string()))?;
+                get_config(manifest.package.and_then(|p| p.metadata).as_ref())?
+            }
+            Err(Error::NoCargoToml) => Config::default(),
+            Err(e) => return Err(e),
+        };
+        let dep = Dependency::from_spec(name, DependencySpec::Version(version))?;
+        let downloader = self.downloader(config)?;
+        let mut runtime = new_runtime()?;
+        Ok(runtime.block_on(dep.download(&downloader))?.bytes)
     }
 
-    fn winmds(&self) -> &[Winmd] {
-        &self.contents.0
+    /// Lists the archive entries of a single package like `unzip -l`
+    fn print_contents(&self, name: String, version: String) -> Result<(), Error> {
+        let bytes = self.download_package(name.clone(), version)?;
+        let reader = std::io::Cursor::new(bytes);
+        let zip_error = |e| Error::Zip(name.clone(), e);
+        let mut zip = zip::ZipArchive::new(reader).map_err(zip_error)?;
+        let mut total = 0;
+        println!("{:>10}  Name", "Length");
+        for i in 0..zip.len() {
+            let file = zip.by_index(i).map_err(zip_error)?;
+            total += file.size();
+            println!("{:>10}  {}", file.size(), file.name());
+        }
+        println!("{:>10}  {} files", total, zip.len());
+        Ok(())
     }
 
-    fn dlls(&self) -> &[Dll] {
-        &self.contents.1
+    /// Lists the target frameworks of a single package, see `Subcommand::Frameworks`
+    fn print_frameworks(&self, name: String, version: String, json: bool) -> Result<(), Error> {
+        let bytes = self.download_package(name.clone(), version.clone())?;
+        let zip = zip::ZipArchive::new(std::io::Cursor::new(bytes))
+            .map_err(|e| Error::Zip(name.clone(), e))?;
+        let frameworks = package_frameworks(zip.file_names());
+        if json {
+            let listing = FrameworksListing {
+                name,
+                version,
+                frameworks: frameworks
+                    .into_iter()
+                    .map(|(framework, folders)| FrameworkFolders { framework, folders })
+                    .collect(),
+            };
+            // Serializing these types can't fail
+            println!("{}", serde_json::to_string(&listing).unwrap());
+            return Ok(());
+        }
+        if frameworks.is_empty() {
+            println!("{} {} has no framework specific files", name, version);
+        }
+        for (framework, folders) in &frameworks {
+            println!("{} ({})", framework, folders.join(", "));
+        }
+        Ok(())
     }
 
-    fn read_contents(zip: &[u8]) -> Result<(Vec<Winmd>, Vec<Dll>), Error> {
-        let reader = std::io::Cursor::new(zip);
-        let mut zip = zip::ZipArchive::new(reader).map_err(|e| Error::Other(Box::new(e)))?;
-        let mut winmds = Vec::new();
-        let mut dlls = Vec::new();
-        for i in 0..zip.len() {
-            let mut file = zip.by_index(i).unwrap();
-            let path = file.sanitized_name();
-            match path.extension() {
-                Some(e)
-                    if e == "winmd"
-                        && path.parent().and_then(Path::to_str) == Some("lib\\uap10.0") =>
-                {
-                    let name = path.file_name().unwrap().to_owned();
-                    let mut contents = Vec::with_capacity(file.size() as usize);
+    /// Inserts a dependency into the manifest, see `Subcommand::Add`
+    fn add_dependency(
+        &self,
+        name: String,
+        version: Option<String>,
+        upgrade: bool,
+    ) -> Result<(), Error> {
+        if !is_valid_package_id(&name) {
+            return Err(Error::InvalidPackageId(name));
+        }
+        let (manifest, mut document) = self.editable_manifest()?;
+        let deps = dependency_table(&mut document)?;
+        let existing = find_dependency(deps, &name);
+        if existing.is_some() && !upgrade {
+            return Err(Error::DependencyExists(name));
+        }
 
-                    if let Err(e) = file.read_to_end(&mut contents) {
-                        eprintln!("Could not read winmd file: {:?}", e);
-                        continue;
+        let version = match version {
+            Some(version) => version,
+            None => {
+                let config = get_config(manifest.package.and_then(|p| p.metadata).as_ref())?;
+                let downloader = self.downloader(config)?;
+                let mut runtime = new_runtime()?;
+                runtime.block_on(resolve_floating(&downloader, &name, "*", None))?
+            }
+        };
+        match existing {
+            Some(key) => {
+                // Keep the options of dependencies given as a table
+                let item = deps.get_mut(&key).unwrap();
+                match item.as_table_like_mut() {
+                    Some(table) if table.contains_key("version") => {
+                        table.insert("version", toml_edit::value(version.as_str()));
                     }
-                    winmds.push(Winmd { name, contents });
-                }
-                Some(e) if e == "dll" && path.starts_with("runtimes") => {
-                    let name: PathBuf = path
-                        .components()
-                        .filter(|c| match c {
-                            std::path::Component::Normal(p) => *p != "native" && *p != "runtimes",
-                            _ => panic!("Unexpected component"),
-                        })
-                        .collect();
-                    let mut contents = Vec::with_capacity(file.size() as usize);
-
-                    if let Err(e) = file.read_to_end(&mut contents) {
-                        eprintln!("Could not read dll: {:?}", e);
-                        continue;
+                    Some(_) => return Err(Error::DependencyExists(name)),
+                    None => {
+                        let decor = item.as_value().map(|v| v.decor().clone());
+                        *item = toml_edit::value(version.as_str());
+                        if let (Some(decor), Some(value)) = (decor, item.as_value_mut()) {
+                            *value.decor_mut() = decor;
+                        }
                     }
-                    dlls.push(Dll { name, contents });
                 }
-                _ => {}
+            }
+            None => {
+                deps.insert(&name, toml_edit::value(version.as_str()));
             }
         }
-        Ok((winmds, dlls))
+        std::fs::write(&self.manifest_path, document.to_string())
+            .map_err(write_error(&self.manifest_path))?;
+        eprintln!(
+            "Added {} {} to {}",
+            name,
+            version,
+            self.manifest_path.display()
+        );
+        Ok(())
     }
-}
 
-fn download_dependencies(deps: Vec<Dependency>) -> Result<Vec<DownloadedDependency>, Error> {
-    tokio::runtime::Runtime::new().unwrap().block_on(async {
-        let results = deps.into_iter().map(|dep| async move {
-            let bytes = dep.download().await?;
-            Ok(DownloadedDependency::new(dep, bytes)?)
-        });
-
-        futures::future::try_join_all(results).await
-    })
-}
+    /// Removes a dependency from the manifest and lock file, see `Subcommand::Remove`
+    fn remove_dependency(&mut self, name: String, delete_files: bool) -> Result<(), Error> {
+        let (manifest, mut document) = self.editable_manifest()?;
+        self.apply_defaults(&get_config(
+            manifest.package.and_then(|p| p.metadata).as_ref(),
+        )?);
+        let deps = dependency_table(&mut document)?;
+        let key = find_dependency(deps, &name).ok_or(Error::NoSuchDependency(name))?;
+        deps.remove(&key);
+        std::fs::write(&self.manifest_path, document.to_string())
+            .map_err(write_error(&self.manifest_path))?;
 
-struct Winmd {
-    name: OsString,
-    contents: Vec<u8>,
-}
+        let lock_path = self.workspace_root().join(lock::LOCK_FILE_NAME);
+        if let Some(mut lockfile) = Lockfile::load(&lock_path)? {
+            lockfile.packages.retain(|p| p.name != key);
+            lockfile.save(&lock_path)?;
+        }
+        if delete_files {
+            let dir = package_dir(&self.out_dir()?, &key)?;
+            match remove_dir(&dir) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(Error::WriteFailed(dir, e))
+                }
+                _ => {}
+            }
+        }
+        eprintln!("Removed {} from {}", key, self.manifest_path.display());
+        Ok(())
+    }
+
+    /// Lists the packages in the output directory, see `Subcommand::ListInstalled`
+    fn list_installed(&mut self, json: bool) -> Result<(), Error> {
+        // The manifest may configure the output directory but isn't needed otherwise
+        match self.read_manifest() {
+            Ok(bytes) => {
+                let manifest = Manifest::from_slice(&bytes)
+                    .map_err(|e| Error::MalformedManifest(e.to_string()))?;
+                self.apply_defaults(&get_config(
+                    manifest.package.and_then(|p| p.metadata).as_ref(),
+                )?);
+            }
+            Err(Error::NoCargoToml) => {}
+            Err(e) => return Err(e),
+        }
+        let out_dir = self.out_dir()?;
+        let lock_path = self.workspace_root().join(lock::LOCK_FILE_NAME);
+        let lockfile = Lockfile::load(&lock_path)?.unwrap_or_default();
+        let mut packages = Vec::new();
+        if out_dir.exists() {
+            for entry in std::fs::read_dir(&out_dir).map_err(read_error(&out_dir))? {
+                let entry = entry.map_err(read_error(&out_dir))?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                // Leftovers of an interrupted install are hidden, see `write_atomically`
+                if !entry.path().is_dir() || name.starts_with('.') {
+                    continue;
+                }
+                let mut files = std::collections::BTreeMap::new();
+                installed_files(&entry.path(), Path::new(""), &mut files)?;
+                packages.push(InstalledPackage {
+                    version: lockfile
+                        .packages
+                        .iter()
+                        .find(|p| p.name == name)
+                        .map(|p| p.version.clone()),
+                    name,
+                    files: files.len(),
+                    size: files.values().map(|f| f.size as u64).sum(),
+                });
+            }
+        }
+        packages.sort_by(|a, b| a.name.cmp(&b.name));
+        if json {
+            // Serializing these types can't fail
+            println!("{}", serde_json::to_string(&packages).unwrap());
+            return Ok(());
+        }
+        if packages.is_empty() {
+            eprintln!("No packages are installed in {}", out_dir.display());
+        }
+        for package in &packages {
+            println!(
+                "{} {} ({} file(s), {} bytes)",
+                package.name,
+                package.version.as_deref().unwrap_or("(not in nuget.lock)"),
+                package.files,
+                package.size
+            );
+        }
+        Ok(())
+    }
+
+    /// Prints how the version of a dependency is chosen, see `Subcommand::Explain`
+    fn explain(&self, name: &str) -> Result<(), Error> {
+        let bytes = self.read_manifest()?;
+        let manifest =
+            Manifest::from_slice(&bytes).map_err(|e| Error::MalformedManifest(e.to_string()))?;
+        let metadata = manifest.package.and_then(|p| p.metadata);
+        let config = get_config(metadata.as_ref())?;
+        let inherited = get_dep_specs(metadata.as_ref())?
+            .iter()
+            .any(|(n, spec)| n.eq_ignore_ascii_case(name) && spec.is_inherited());
+        let dep = get_deps(metadata.as_ref(), || self.workspace_metadata(&bytes))?
+            .into_iter()
+            .find(|d| d.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| Error::NoSuchDependency(name.to_owned()))?;
+        println!(
+            "{} is declared in {} as `{}`{}",
+            dep.name,
+            self.manifest_path.display(),
+            dep.requested,
+            if inherited {
+                ", inherited from the workspace"
+            } else {
+                ""
+            }
+        );
+        if self
+            .exclude_package
+            .iter()
+            .any(|n| n.eq_ignore_ascii_case(&dep.name))
+        {
+            println!("It isn't installed because it's passed to --exclude-package");
+            return Ok(());
+        }
+        let lockfile =
+            Lockfile::load(&self.workspace_root().join(lock::LOCK_FILE_NAME))?.unwrap_or_default();
+        let locked = lockfile.packages.iter().find(|p| p.name == dep.name);
+        match locked {
+            Some(locked) => println!(
+                "nuget.lock records version {} from {}",
+                locked.version,
+                locked.source.as_deref().unwrap_or("its url or the cache")
+            ),
+            None => println!("nuget.lock has no entry for it yet"),
+        }
+        let floating = match (&dep.url, FloatingVersion::parse(&dep.version)) {
+            (Some(url), _) => {
+                println!(
+                    "It's downloaded from {} so the version is only recorded, not resolved",
+                    url
+                );
+                return Ok(());
+            }
+            (None, None) => {
+                println!(
+                    "{} is an exact version so it's installed as is",
+                    dep.version
+                );
+                return Ok(());
+            }
+            (None, Some(floating)) => floating,
+        };
+        if let Some(locked) = locked.filter(|l| self.locked && floating.matches(&l.version)) {
+            println!(
+                "With --locked the recorded version {} is kept since it still matches, without looking at the published versions",
+                locked.version
+            );
+            return Ok(());
+        }
+        let locked_source = locked
+            .and_then(|l| l.source.as_deref())
+            .filter(|_| self.locked);
+        if let Some(source) = locked_source {
+            println!("With --locked only {} is considered", source);
+        }
+        if let Some(as_of) = &self.as_of {
+            println!(
+                "With --as-of only versions published by {} UTC are considered",
+                as_of.replace('T', " ")
+            );
+        }
+        let downloader = self.downloader(config)?;
+        let mut runtime = new_runtime()?;
+        let published =
+            runtime.block_on(published_versions(&downloader, &dep.name, locked_source))?;
+        let mut all = Vec::new();
+        for (source, mut versions) in published {
+            versions.sort_by(|a, b| nuspec::compare_versions(a, b));
+            println!("{} publishes: {}", source, list_or_none(&versions));
+            all.extend(versions);
+        }
+        // Several sources may publish the same version
+        let mut matching: Vec<String> = all.into_iter().filter(|v| floating.matches(v)).collect();
+        matching.sort_by(|a, b| nuspec::compare_versions(a, b));
+        matching.dedup_by(|a, b| nuspec::compare_versions(a, b).is_eq());
+        println!("Matching `{}`: {}", dep.version, list_or_none(&matching));
+        match floating.pick(matching.iter().map(String::as_str), self.prefer_lowest) {
+            Some(chosen) if self.prefer_lowest => {
+                println!("{} is chosen as the lowest matching version", chosen)
+            }
+            Some(chosen) => println!("{} is chosen as the highest matching version", chosen),
+            None => println!("No version matches so the install fails"),
+        }
+        Ok(())
+    }
+
+    /// Reads the manifest both parsed and in a form that can be edited without losing
+    /// its formatting
+    fn editable_manifest(&self) -> Result<(Manifest, toml_edit::DocumentMut), Error> {
+        if self.reads_stdin() {
+            return Err(Error::StdinManifest);
+        }
+        let bytes = self.read_manifest()?;
+        let manifest =
+            Manifest::from_slice(&bytes).map_err(|e| Error::MalformedManifest(e.to_string()))?;
+        let document = String::from_utf8(bytes)
+            .map_err(|e| Error::MalformedManifest(e.to_string()))?
+            .parse::<toml_edit::DocumentMut>()
+            .map_err(|e| Error::MalformedManifest(e.to_string()))?;
+        Ok((manifest, document))
+    }
+
+    /// How many packages are worked on at the same time, see `--jobs` and `--adaptive-jobs`
+    fn concurrency(&self) -> Concurrency {
+        let jobs = match self.jobs {
+            0 => std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get),
+            jobs => jobs,
+        };
+        if self.adaptive_jobs || self.min_jobs.is_some() || self.max_jobs.is_some() {
+            Concurrency::adaptive(
+                jobs,
+                self.min_jobs.unwrap_or(1),
+                self.max_jobs.unwrap_or(jobs),
+            )
+        } else {
+            Concurrency::fixed(jobs)
+        }
+    }
+
+    fn downloader(&self, config: Config) -> Result<Downloader, Error> {
+        let temp_dir = self.temp_dir.clone().unwrap_or_else(std::env::temp_dir);
+        // Fail before anything is downloaded rather than after the first package arrives
+        Spool::check_dir(&temp_dir)?;
+        let client = self.client()?;
+        let sources = self.sources(config)?;
+        Ok(Downloader {
+            sources: order_mirrors(sources, &client)?,
+            client,
+            max_download_size: self.max_download_size,
+            throttle: self.max_rate.map(|r| Arc::new(Throttle::new(r.get()))),
+            retries: self.retries,
+            retry_budget: self.retry_budget.map(|b| Arc::new(AtomicUsize::new(b))),
+            cache: self
+                .cache_dir
+                .clone()
+                .or_else(Cache::default_dir)
+                .map(Cache::new),
+            prefer_cache: self.prefer_cache,
+            refresh_index: self.refresh_index,
+            temp_dir,
+            as_of: self.as_of.clone(),
+            prefer_lowest: self.prefer_lowest,
+            snapshot: self
+                .registration_snapshot
+                .as_deref()
+                .map(Snapshot::load)
+                .transpose()?
+                .map(Arc::new),
+            symbols: self.symbols,
+            events: self.events(),
+            concurrency: Arc::new(self.concurrency()),
+            retained: if self.reuse_nupkgs {
+                Some(self.out_dir()?)
+            } else {
+                None
+            },
+        })
+    }
+
+    fn extract_options(&self) -> ExtractOptions {
+        ExtractOptions {
+            winmd_folders: if self.winmd_folder.is_empty() {
+                DEFAULT_WINMD_FOLDERS
+                    .iter()
+                    .map(|f| f.to_string())
+                    .collect()
+            } else {
+                self.winmd_folder.clone()
+            },
+            frameworks: self.frameworks(),
+            strict: self.strict,
+            preserve_mtime: self.preserve_mtime,
+            include_docs: self.include_docs,
+            max_file_size: self.max_file_size,
+            min_file_size: self.min_file_size,
+            resolve_symlinks: self.resolve_symlinks,
+            include_native_runtimes: self.include_native_runtimes,
+            include_tools: self.include_tools,
+            continue_on_extract_error: self.continue_on_extract_error,
+            no_extract: self.no_extract,
+        }
+    }
+
+    /// The sources to download from, in order
+    ///
+    /// `--source` replaces any sources configured in the manifest and nuget.org is used
+    /// when there are none.
+    fn sources(&self, config: Config) -> Result<Vec<Source>, Error> {
+        let mut sources = match &self.source {
+            Some(url) => vec![Source::new(url.clone(), None)],
+            None if !config.sources.is_empty() => {
+                let mut sources = config
+                    .sources
+                    .into_iter()
+                    .map(Source::from_config)
+                    .collect::<Result<Vec<_>, _>>()?;
+                // Like other paths in Cargo.toml, local feeds are relative to the manifest
+                for source in &mut sources {
+                    source.resolve_relative(&self.workspace_root());
+                }
+                sources
+            }
+            None => vec![Source::new(source::DEFAULT_SOURCE.to_owned(), None)],
+        };
+        for source in &mut sources {
+            self.check_insecure_http(source.url())?;
+            if let (Some(key), false) = (&self.api_key, source.has_api_key()) {
+                source.set_api_key(key.clone());
+            }
+            if let (Some(helper), false) = (&self.credential_helper, source.has_api_key()) {
+                if source.local_dir().is_none() {
+                    source.set_credential_helper(helper.clone());
+                }
+            }
+            for (name, value) in &self.header {
+                source.add_header(name.clone(), value.clone());
+            }
+            if source.has_proxy() {
+                source.set_client(self.client_builder()?)?;
+            }
+        }
+        Ok(sources)
+    }
+
+    /// Refuses plain http urls unless `--allow-insecure-http` was passed, and warns when it was
+    fn check_insecure_http(&self, url: &str) -> Result<(), Error> {
+        let insecure = reqwest::Url::parse(url)
+            .map(|u| u.scheme() == "http")
+            .unwrap_or(false);
+        if !insecure {
+            return Ok(());
+        }
+        if !self.allow_insecure_http {
+            return Err(Error::InsecureHttp(url.to_owned()));
+        }
+        warn(format!(
+            "{} uses unencrypted http so anything downloaded from it can be tampered with",
+            url
+        ));
+        Ok(())
+    }
+
+    fn client(&self) -> Result<reqwest::Client, Error> {
+        self.client_builder()?.build().map_err(Error::Client)
+    }
+
+    /// A builder for http clients configured with the user agent and TLS settings
+    fn client_builder(&self) -> Result<reqwest::ClientBuilder, Error> {
+        let protocol = match self.min_tls.as_str() {
+            "1.0" => native_tls::Protocol::Tlsv10,
+            "1.1" => native_tls::Protocol::Tlsv11,
+            _ => native_tls::Protocol::Tlsv12,
+        };
+        let tls = native_tls::TlsConnector::builder()
+            .min_protocol_version(Some(protocol))
+            .build()
+            .map_err(Error::Tls)?;
+        Ok(reqwest::Client::builder()
+            .user_agent(&self.user_agent)
+            .use_preconfigured_tls(tls))
+    }
+
+    fn read_manifest(&self) -> Result<Vec<u8>, Error> {
+        if self.reads_stdin() {
+            let mut bytes = Vec::new();
+            std::io::stdin()
+                .read_to_end(&mut bytes)
+                .map_err(|_| Error::NoCargoToml)?;
+            return Ok(bytes);
+        }
+        std::fs::read(&self.manifest_path).map_err(|_| Error::NoCargoToml)
+    }
+
+    fn reads_stdin(&self) -> bool {
+        self.manifest_path.as_os_str() == "-"
+    }
+
+    /// The directory `target` lives in
+    ///
+    /// This is the manifest's directory, or the current directory when the manifest is read from stdin.
+    fn workspace_root(&self) -> PathBuf {
+        // TODO: improve this
+        if self.reads_stdin() {
+            return PathBuf::new();
+        }
+        self.manifest_path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    }
+
+    /// The `[workspace.metadata]` table of the workspace the manifest belongs to
+    ///
+    /// The workspace's manifest is the closest one with a `[workspace]` table, starting
+    /// with the manifest itself.
+    fn workspace_metadata(&self, manifest: &[u8]) -> Result<Option<Value>, Error> {
+        let parse = |bytes: &[u8]| {
+            toml::from_slice::<Value>(bytes).map_err(|e| Error::MalformedManifest(e.to_string()))
+        };
+        let mut workspace = parse(manifest)?.get("workspace").cloned();
+        if workspace.is_none() {
+            let dir = self.workspace_root();
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                &dir
+            };
+            let dir = dir.canonicalize().map_err(read_error(dir))?;
+            for parent in dir.ancestors().skip(1) {
+                if let Ok(bytes) = std::fs::read(parent.join("Cargo.toml")) {
+                    workspace = parse(&bytes)?.get("workspace").cloned();
+                    if workspace.is_some() {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(workspace.and_then(|w| w.get("metadata").cloned()))
+    }
+
+    fn out_dir(&self) -> Result<PathBuf, Error> {
+        if let Some(dir) = self.vendor.as_ref().or(self.vendored.as_ref()) {
+            return Ok(dir.clone());
+        }
+        if self.use_out_dir {
+            return std::env::var_os("OUT_DIR")
+                .map(|dir| PathBuf::from(dir).join("nuget"))
+                .ok_or(Error::NoOutDir);
+        }
+        Ok(match &self.out_dir {
+            Some(dir) => dir.clone(),
+            None => self.workspace_root().join("target").join("nuget"),
+        })
+    }
+}
+
+/// Warns before extracting anything if the filesystem `dir` is on can't fit `needed` bytes
+///
+/// This is only an early heads up, running out of space is still reported by the write
+/// that fails.
+fn warn_if_low_on_space(dir: &Path, needed: u64) {
+    // The directory often doesn't exist yet so check the closest ancestor that does
+    let existing = dir
+        .ancestors()
+        .find(|d| d.exists())
+        .unwrap_or_else(|| Path::new("."));
+    match fs2::available_space(existing) {
+        Ok(available) if available < needed => warn(format!(
+            "installing needs {} bytes but only {} are free in {}",
+            needed,
+            available,
+            existing.display()
+        )),
+        _ => {}
+    }
+}
+
+/// The directory the package `name` is installed into inside `out_dir`
+///
+/// Package ids are validated when they're read from the manifest, but as the directory is
+/// replaced or deleted as a whole this makes sure nothing outside of `out_dir` can ever be
+/// touched: the id has to be a single plain path component, and a directory that already
+/// exists has to resolve to somewhere inside `out_dir`, so not even a symlink left there can
+/// lead elsewhere.
+fn package_dir(out_dir: &Path, name: &str) -> Result<PathBuf, Error> {
+    let mut components = Path::new(name).components();
+    let plain = matches!(
+        (components.next(), components.next()),
+        (Some(std::path::Component::Normal(_)), None)
+    );
+    if !plain || name.contains(['/', '\\']) {
+        return Err(Error::UnsafePackageDir(name.to_owned()));
+    }
+    let dir = out_dir.join(name);
+    if let (Ok(base), Ok(resolved)) = (out_dir.canonicalize(), dir.canonicalize()) {
+        if resolved.parent() != Some(base.as_path()) {
+            return Err(Error::UnsafePackageDir(name.to_owned()));
+        }
+    }
+    Ok(dir)
+}
+
+/// Fills `dir` using `write`, replacing whatever was there before
+///
+/// Files are written into a temporary sibling directory which is only renamed into
+/// place once `write` succeeds, so `dir` always holds either the previous complete
+/// install or the new one and never a partial mix of the two.
+fn write_atomically(
+    dir: &Path,
+    write: impl FnOnce(&Path) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let parent = dir.parent().unwrap_or_else(|| Path::new(""));
+    let name = dir.file_name().unwrap().to_string_lossy();
+    let tmp = parent.join(format!(".{}.tmp", name));
+    let old = parent.join(format!(".{}.old", name));
+    // Clean up after any previous run that was interrupted
+    for leftover in &[&tmp, &old] {
+        if leftover.exists() {
+            remove_dir(leftover).map_err(write_error(leftover))?;
+        }
+    }
+
+    std::fs::create_dir_all(&tmp).map_err(write_error(&tmp))?;
+    if let Err(e) = write(&tmp) {
+        let _ = remove_dir(&tmp);
+        return Err(e);
+    }
+
+    // Directories can't be renamed over each other on all platforms so move the
+    // old install out of the way first
+    let had_previous = dir.exists();
+    if had_previous {
+        std::fs::rename(dir, &old).map_err(write_error(dir))?;
+    }
+    if let Err(e) = std::fs::rename(&tmp, dir) {
+        if had_previous {
+            let _ = std::fs::rename(&old, dir);
+        }
+        let _ = remove_dir(&tmp);
+        return Err(write_error(dir)(e));
+    }
+    // The new files are in place already, and files of the old install may still be open
+    // (e.g. a winmd loaded by an IDE on Windows), so what's left is cleaned up next time
+    if had_previous {
+        if let Err(e) = remove_dir(&old) {
+            warn(format!(
+                "Could not remove the previous install {}: {}",
+                old.display(),
+                e
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The files an install would change in the output directory, see `--diff`
+#[derive(Default)]
+struct Changes {
+    added: usize,
+    removed: usize,
+    changed: usize,
+}
+
+impl Changes {
+    /// Prints how the files installed in a package's directory differ from `files`, the
+    /// files the install would put there
+    fn compare(&mut self, name: &str, dir: &Path, files: &[FileReport]) -> Result<(), Error> {
+        let mut installed = std::collections::BTreeMap::new();
+        if dir.exists() {
+            installed_files(dir, Path::new(""), &mut installed)?;
+        }
+        let mut lines = Vec::new();
+        for file in files {
+            match installed.remove(&file.path) {
+                None => {
+                    lines.push(format!("  + {}", file.path));
+                    self.added += 1;
+                }
+                Some(installed) if installed.sha256 != file.sha256 => {
+                    lines.push(format!("  ~ {}", file.path));
+                    self.changed += 1;
+                }
+                Some(_) => {}
+            }
+        }
+        for path in installed.keys() {
+            lines.push(format!("  - {}", path));
+            self.removed += 1;
+        }
+        if !lines.is_empty() {
+            lines.sort_by(|a, b| a[4..].cmp(&b[4..]));
+            println!("{}:", name);
+            for line in lines {
+                println!("{}", line);
+            }
+        }
+        Ok(())
+    }
+
+    fn print_summary(&self) {
+        if self.added + self.removed + self.changed == 0 {
+            eprintln!("The install wouldn't change any files");
+            return;
+        }
+        eprintln!(
+            "The install would add {} file(s), remove {} and change {}",
+            self.added, self.removed, self.changed
+        );
+    }
+}
+
+/// Describes the files below `dir` like the install report does, by their path relative to
+/// the package's directory
+fn installed_files(
+    dir: &Path,
+    relative: &Path,
+    files: &mut std::collections::BTreeMap<String, FileReport>,
+) -> Result<(), Error> {
+    let path = dir.join(relative);
+    for entry in std::fs::read_dir(&path).map_err(read_error(&path))? {
+        let entry = entry.map_err(read_error(&path))?;
+        let relative = relative.join(entry.file_name());
+        if entry.path().is_dir() {
+            installed_files(dir, &relative, files)?;
+        } else {
+            let contents = std::fs::read(entry.path()).map_err(read_error(&entry.path()))?;
+            let file = FileReport::new(&relative, &contents);
+            files.insert(file.path.clone(), file);
+        }
+    }
+    Ok(())
+}
+
+/// Sets or clears the read-only flag of every file below `dir`, see `--read-only`
+///
+/// Symlinks are left alone so the files they point to keep their permissions.
+fn set_read_only(dir: &Path, read_only: bool) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            set_read_only(&entry.path(), read_only)?;
+        } else if file_type.is_file() {
+            let mut permissions = entry.metadata()?.permissions();
+            if permissions.readonly() != read_only {
+                permissions.set_readonly(read_only);
+                std::fs::set_permissions(entry.path(), permissions)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes `dir` and everything in it, including files made read-only by `--read-only`
+fn remove_dir(dir: &Path) -> std::io::Result<()> {
+    // Windows refuses to delete read-only files
+    let _ = set_read_only(dir, false);
+    std::fs::remove_dir_all(dir)
+}
+
+/// Quotes `path` for a POSIX shell or xargs if it contains anything but plain characters
+fn shell_quote(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    let plain = |c: char| c.is_ascii_alphanumeric() || "/._-+=:,@%".contains(c);
+    if !path.is_empty() && path.chars().all(plain) {
+        return path.into_owned();
+    }
+    format!("'{}'", path.replace('\'', "'\\''"))
+}
+
+/// Joins the items of a list for an error message, saying `none` if it is empty
+fn list_or_none(items: &[String]) -> String {
+    if items.is_empty() {
+        return "none".to_owned();
+    }
+    items.join(", ")
+}
+
+fn license_or_none(license: &Option<License>) -> String {
+    license
+        .as_ref()
+        .map_or_else(|| "no license".to_owned(), License::to_string)
+}
+
+/// Writes a depfile in the format rustc uses for `--emit=dep-info`
+///
+/// Every input also gets an empty rule so deleting one doesn't break the outer build.
+fn write_depfile(path: &Path, outputs: &[PathBuf], inputs: &[PathBuf]) -> Result<(), Error> {
+    // Make only needs spaces escaped, which is all rustc does as well
+    let escape = |path: &PathBuf| path.to_string_lossy().replace(' ', "\\ ");
+    let outputs: Vec<String> = outputs.iter().map(escape).collect();
+    let inputs: Vec<String> = inputs.iter().map(escape).collect();
+    let mut contents = format!("{}: {}\n", outputs.join(" "), inputs.join(" "));
+    for input in &inputs {
+        contents.push_str(&format!("\n{}:\n", input));
+    }
+    std::fs::write(path, contents).map_err(write_error(path))
+}
+
+/// Writes the paths of the installed winmds, sorted and deduplicated so the file only changes
+/// when the winmds do
+fn write_winmd_list(path: &Path, winmds: &[PathBuf], separator: &str) -> Result<(), Error> {
+    let mut winmds: Vec<String> = winmds
+        .iter()
+        .map(|w| w.to_string_lossy().into_owned())
+        .collect();
+    winmds.sort();
+    winmds.dedup();
+    let mut contents = winmds.join(separator);
+    if !contents.is_empty() {
+        contents.push('\n');
+    }
+    std::fs::write(path, contents).map_err(write_error(path))
+}
+
+/// Expands a leading `~` and any `$VAR`/`${VAR}` references in a user supplied path
+fn expand_path(path: &OsStr) -> Result<PathBuf, OsString> {
+    // Paths that aren't valid UTF-8 can't contain anything we know how to expand
+    let path = match path.to_str() {
+        Some(p) => p,
+        None => return Ok(PathBuf::from(path)),
+    };
+
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") || rest.starts_with("~\\") {
+        let home = std::env::var("HOME")
+            .or_else(|_| std::env::var("USERPROFILE"))
+            .map_err(|_| OsString::from("could not determine the home directory to expand `~`"))?;
+        expanded.push_str(&home);
+        rest = &rest[1..];
+    }
+
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        let (name, remainder) = if let Some(braced) = rest.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => return Err(format!("unterminated `${{` in path `{}`", path).into()),
+            }
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+        if name.is_empty() {
+            // A lone `$` isn't a variable reference so keep it as is
+            expanded.push('$');
+        } else {
+            let value = std::env::var(name).map_err(|_| {
+                OsString::from(format!("environment variable `{}` is not set", name))
+            })?;
+            expanded.push_str(&value);
+        }
+        rest = remainder;
+    }
+    expanded.push_str(rest);
+
+    Ok(PathBuf::from(expanded))
+}
+
+/// Parses the date given to `--as-of` as either a day, which includes the whole day, or
+/// an RFC 3339 timestamp
+fn parse_as_of(date: &str) -> Result<String, String> {
+    let timestamp = if date.len() == "YYYY-MM-DD".len() {
+        format!("{}T23:59:59Z", date)
+    } else {
+        date.to_owned()
+    };
+    let time = humantime::parse_rfc3339_weak(&timestamp).map_err(|_| {
+        format!(
+            "`{}` is neither a date (YYYY-MM-DD) nor a UTC timestamp (YYYY-MM-DDTHH:MM:SSZ)",
+            date
+        )
+    })?;
+    let formatted = humantime::format_rfc3339_seconds(time).to_string();
+    // Publish dates are compared as strings so keep only the parts every feed includes
+    Ok(formatted[.."YYYY-MM-DDTHH:MM:SS".len()].to_owned())
+}
+
+/// Reads the dependencies from the package metadata
+///
+/// `workspace` gives the `[workspace.metadata]` table and is only called when a dependency
+/// is inherited from the workspace.
+fn get_deps(
+    metadata: Option<&Value>,
+    workspace: impl FnOnce() -> Result<Option<Value>, Error>,
+) -> Result<Vec<Dependency>, Error> {
+    let specs = get_dep_specs(metadata)?;
+    let workspace_specs = if specs.iter().any(|(_, spec)| spec.is_inherited()) {
+        match workspace()? {
+            Some(metadata) if metadata.get("nuget_dependencies").is_some() => {
+                get_dep_specs(Some(&metadata))?
+            }
+            _ => Vec::new(),
+        }
+    } else {
+        Vec::new()
+    };
+    specs
+        .into_iter()
+        .map(|(name, spec)| {
+            let spec = if spec.is_inherited() {
+                workspace_specs
+                    .iter()
+                    .find(|(n, s)| *n == name && !s.is_inherited())
+                    .map(|(_, s)| s.clone())
+                    .ok_or_else(|| Error::MissingWorkspaceDependency(name.clone()))?
+            } else {
+                spec
+            };
+            Dependency::from_spec(name, spec)
+        })
+        .collect()
+}
+
+/// Parses the `nuget_dependencies` table of the given metadata
+fn get_dep_specs(metadata: Option<&Value>) -> Result<Vec<(String, DependencySpec)>, Error> {
+    let deps = match metadata.and_then(|m| m.get("nuget_dependencies")) {
+        Some(Value::Table(deps)) => deps,
+        _ => {
+            return Err(Error::MalformedManifest(
+                "nuget_dependencies is not a table".to_owned(),
+            ))
+        }
+    };
+    deps.iter()
+        .map(|(key, value)| {
+            if !is_valid_package_id(key) {
+                return Err(Error::InvalidPackageId(key.clone()));
+            }
+            let spec = value
+                .clone()
+                .try_into()
+                .map_err(|e| Error::MalformedManifest(e.to_string()))?;
+            Ok((key.clone(), spec))
+        })
+        .collect()
+}
+
+/// Parses a package passed to `install` as `name@version`
+fn parse_package_version(s: &str) -> Result<(String, String), String> {
+    let (name, version) = s
+        .rsplit_once('@')
+        .ok_or_else(|| format!("`{}` is not written as name@version", s))?;
+    if !is_valid_package_id(name) {
+        return Err(format!("`{}` is not a valid package id", name));
+    }
+    if version.is_empty() {
+        return Err(format!("`{}` is missing a version after the @", s));
+    }
+    Ok((name.to_owned(), version.to_owned()))
+}
+
+/// Whether `id` follows NuGet's package id grammar
+///
+/// Ids are at most 100 characters of letters, digits and `_`, optionally separated by
+/// single `.` or `-` characters.
+fn is_valid_package_id(id: &str) -> bool {
+    const MAX_LENGTH: usize = 100;
+    let is_separator = |c: char| c == '.' || c == '-';
+    id.len() <= MAX_LENGTH
+        && id
+            .split(is_separator)
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_alphanumeric() || c == '_'))
+}
+
+/// Settings from the `[package.metadata.nuget]` table
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Sources to download packages from, tried in order
+    #[serde(default)]
+    sources: Vec<SourceConfig>,
+    /// The frameworks extracted when --framework isn't given
+    framework: Option<OneOrMany>,
+    /// The output directory used when none is given on the command line, relative to the
+    /// manifest
+    out_dir: Option<PathBuf>,
+    #[serde(default)]
+    include_docs: bool,
+    #[serde(default)]
+    include_native_runtimes: bool,
+    #[serde(default)]
+    include_tools: bool,
+}
+
+/// A setting given either as a single string or a list of them
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<OneOrMany> for Vec<String> {
+    fn from(value: OneOrMany) -> Self {
+        match value {
+            OneOrMany::One(one) => vec![one],
+            OneOrMany::Many(many) => many,
+        }
+    }
+}
+
+fn get_config(metadata: Option<&Value>) -> Result<Config, Error> {
+    match metadata.and_then(|m| m.get("nuget")) {
+        Some(config) => config
+            .clone()
+            .try_into()
+            .map_err(|e| Error::MalformedManifest(e.to_string())),
+        None => Ok(Config::default()),
+    }
+}
+
+/// A dependency as written in the `nuget_dependencies` table
+///
+/// Either just a version (`"Win2D.uwp" = "1.25.0"`), a table with more options
+/// (`"Win2D.uwp" = { version = "1.25.0", exclude = ["*.Test.winmd"] }`) or
+/// `{ workspace = true }` to use the workspace's declaration of the dependency.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum DependencySpec {
+    Version(String),
+    Detailed(DetailedDependencySpec),
+    Inherited(InheritedDependencySpec),
+}
+
+impl DependencySpec {
+    fn is_inherited(&self) -> bool {
+        matches!(self, DependencySpec::Inherited(i) if i.workspace)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InheritedDependencySpec {
+    workspace: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DetailedDependencySpec {
+    version: String,
+    /// Globs matched against archive paths of files that shouldn't be extracted
+    #[serde(default)]
+    exclude: Vec<String>,
+    /// Globs matched against archive paths of exactly the files to extract, replacing the
+    /// folders winmds are normally looked for in
+    #[serde(default)]
+    paths: Vec<String>,
+    /// A url the nupkg is downloaded from directly instead of from the source
+    url: Option<String>,
+    /// Authors the package's nuspec has to list, so a package of the same id published by
+    /// someone else isn't installed
+    #[serde(default)]
+    authors: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Dependency {
+    name: String,
+    version: String,
+    /// The version as written in the manifest, before floating versions are resolved
+    requested: String,
+    exclude: Vec<Pattern>,
+    paths: Vec<Pattern>,
+    url: Option<String>,
+    /// Authors the package has to list
+    authors: Vec<String>,
+    /// The source the lock file requires the package to be downloaded from
+    locked_source: Option<String>,
+    /// The hash the lock file recorded for the package, which cached copies have to match
+    locked_sha256: Option<String>,
+}
+
+impl Dependency {
+    fn from_spec(name: String, spec: DependencySpec) -> Result<Self, Error> {
+        match spec {
+            DependencySpec::Version(version) => Ok(Self {
+                name,
+                requested: version.clone(),
+                version,
+                exclude: Vec::new(),
+                paths: Vec::new(),
+                url: None,
+                authors: Vec::new(),
+                locked_source: None,
+                locked_sha256: None,
+            }),
+            DependencySpec::Detailed(spec) => {
+                let patterns = |globs: &[String]| {
+                    globs
+                        .iter()
+                        .map(|p| Pattern::new(p).map_err(|_| Error::InvalidPattern(p.clone())))
+                        .collect::<Result<Vec<_>, _>>()
+                };
+                Ok(Self {
+                    name,
+                    requested: spec.version.clone(),
+                    version: spec.version,
+                    exclude: patterns(&spec.exclude)?,
+                    paths: patterns(&spec.paths)?,
+                    url: spec.url,
+                    authors: spec.authors,
+                    locked_source: None,
+                    locked_sha256: None,
+                })
+            }
+            // Inherited dependencies have been replaced by the workspace's declaration so
+            // this is `workspace = false`, which doesn't say which version to use
+            DependencySpec::Inherited(_) => Err(Error::MalformedManifest(format!(
+                "the dependency {} sets `workspace = false` without a version",
+                name
+            ))),
+        }
+    }
+
+    /// Whether the file at the given archive path has been excluded from extraction
+    fn is_excluded(&self, path: &str) -> bool {
+        self.exclude.iter().any(|p| p.matches(path))
+    }
+
+    /// The position of the first of the dependency's `paths` matching the archive path
+    ///
+    /// Earlier paths take precedence when files of the same name match several of them.
+    fn listed_precedence(&self, path: &str) -> Option<usize> {
+        self.paths.iter().position(|p| p.matches(path))
+    }
+
+    /// Downloads the nupkg, or takes it from the cache with `--prefer-cache`
+    ///
+    /// Otherwise a cached package is revalidated with the source it came from, which only
+    /// sends the package again if it changed since.
+    async fn download(&self, downloader: &Downloader) -> Result<Download, Error> {
+        if let Some(bytes) = self.get_retained(downloader) {
+            return Ok(Download {
+                bytes,
+                source: None,
+                cached: true,
+                validators: None,
+            });
+        }
+        // Packages downloaded from a direct url are only identified by the url so aren't cached
+        let cache = downloader.cache.as_ref().filter(|_| self.url.is_none());
+        let cached = cache.and_then(|c| self.get_cached(c));
+        if downloader.prefer_cache {
+            if let Some(bytes) = cached {
+                return Ok(Download {
+                    bytes,
+                    source: None,
+                    cached: true,
+                    validators: None,
+                });
+            }
+        }
+
+        let (bytes, source, validators) = match &self.url {
+            Some(url) => {
+                // Only send credentials if the url is on the same host as a source
+                let source = downloader.sources.iter().find(|s| s.same_host(url));
+                let bytes = download_with_retries(downloader, source, &self.name, url).await?;
+                (bytes, None, None)
+            }
+            None => {
+                let validators = cache
+                    .filter(|_| cached.is_some())
+                    .and_then(|c| c.get_validators(&self.name, &self.version));
+                let (fetched, source) = match self
+                    .download_from_sources(downloader, PackageKind::Package, validators.as_ref())
+                    .await
+                {
+                    Err(Error::PackageNotFound(name)) => {
+                        return Err(not_found(downloader, name).await)
+                    }
+                    result => result?,
+                };
+                match (fetched, cached) {
+                    (Fetched::NotModified(_), Some(bytes)) => {
+                        return Ok(Download {
+                            bytes,
+                            source: Some(source),
+                            cached: true,
+                            validators,
+                        })
+                    }
+                    (Fetched::Modified(bytes, validators), _) => {
+                        (bytes, Some(source), Some(validators))
+                    }
+                    (fetched @ Fetched::NotModified(_), None) => {
+                        (fetched.into_bytes()?, None, None)
+                    }
+                }
+            }
+        };
+        // Packages of local feeds are already on disk
+        let local = downloader
+            .sources
+            .iter()
+            .any(|s| source.as_deref().is_some_and(|u| s.is(u)) && s.local_dir().is_some());
+        if let Some(cache) = cache.filter(|_| !local) {
+            if let Err(e) = cache.put(&self.name, &self.version, &bytes, validators.as_ref()) {
+                warn(format!(
+                    "Could not cache {} {}: {}",
+                    self.name, self.version, e
+                ));
+            }
+        }
+        Ok(Download {
+            bytes,
+            source,
+            cached: false,
+            validators,
+        })
+    }
+
+    /// The nupkg `--no-extract` kept in the output directory, if it's to be used and matches
+    /// the hash in the lock file
+    fn get_retained(&self, downloader: &Downloader) -> Option<Vec<u8>> {
+        let dir = package_dir(downloader.retained.as_ref()?, &self.name).ok()?;
+        let bytes = std::fs::read(dir.join(self.nupkg_name())).ok()?;
+        match &self.locked_sha256 {
+            Some(expected) if *expected != format!("{:x}", Sha256::digest(&bytes)) => None,
+            _ => Some(bytes),
+        }
+    }
+
+    /// The file name the package is written under with `--no-extract`, the one NuGet uses in
+    /// its own caches
+    fn nupkg_name(&self) -> String {
+        format!(
+            "{}.{}.nupkg",
+            self.name.to_lowercase(),
+            self.version.to_lowercase()
+        )
+    }
+
+    /// The cached package, unless it doesn't match the hash in the lock file
+    ///
+    /// A package damaged in the cache would fail the hash check of every install, so it's
+    /// evicted and downloaded again instead.
+    fn get_cached(&self, cache: &Cache) -> Option<Vec<u8>> {
+        let bytes = cache.get(&self.name, &self.version)?;
+        match &self.locked_sha256 {
+            Some(expected) if *expected != format!("{:x}", Sha256::digest(&bytes)) => {
+                warn(format!(
+                    "the cached {} {} doesn't match the hash in nuget.lock, so it's downloaded again",
+                    self.name, self.version
+                ));
+                if let Err(e) = cache.remove(&self.name, &self.version) {
+                    warn(format!(
+                        "Could not remove {} {} from the cache: {}",
+                        self.name, self.version, e
+                    ));
+                }
+                None
+            }
+            _ => Some(bytes),
+        }
+    }
+
+    /// Downloads the dependency's symbol package, if any of the sources have one
+    ///
+    /// Symbols are only a debugging aid so failing to download them is just a warning.
+    async fn download_symbols(&self, downloader: &Downloader) -> Option<Vec<u8>> {
+        match self
+            .download_from_sources(downloader, PackageKind::Symbols, None)
+            .await
+            .and_then(|(fetched, _)| fetched.into_bytes())
+        {
+            Ok(bytes) => Some(bytes),
+            Err(Error::PackageNotFound(_)) | Err(Error::LockedSourceMismatch(..)) => {
+                warn(format!(
+                    "no symbol package was found for {} {}",
+                    self.name, self.version
+                ));
+                None
+            }
+            Err(e) => {
+                warn(format!(
+                    "could not download symbols for {} {}: {}",
+                    self.name, self.version, e
+                ));
+                None
+            }
+        }
+    }
+
+    /// Downloads the given kind of package from the first source that has it, returning the
+    /// url of that source too
+    ///
+    /// A dependency with a locked source is only looked for in that source. `validators` make
+    /// the request conditional if the package is requested from the url they came from.
+    async fn download_from_sources(
+        &self,
+        downloader: &Downloader,
+        kind: PackageKind,
+        validators: Option<&Validators>,
+    ) -> Result<(Fetched, String), Error> {
+        let sources: Vec<_> = match &self.locked_source {
+            Some(locked) => {
+                let source = downloader
+                    .sources
+                    .iter()
+                    .find(|s| s.is(locked))
+                    .ok_or_else(|| Error::UnknownLockedSource(self.name.clone(), locked.clone()))?;
+                vec![source]
+            }
+            None => downloader.sources.iter().collect(),
+        };
+        for source in sources {
+            let base = source
+                .package_base(
+                    &downloader.client,
+                    downloader.cache.as_ref(),
+                    downloader.refresh_index,
+                )
+                .await?;
+            if let PackageBase::Local(_) = base {
+                let path = match base.find_local(self, kind) {
+                    Some(p) => p,
+                    None => continue,
+                };
+                let bytes = std::fs::read(&path).map_err(read_error(&path))?;
+                let validators = Validators {
+                    url: path.to_string_lossy().into_owned(),
+                    etag: None,
+                    last_modified: None,
+                };
+                return Ok((
+                    Fetched::Modified(bytes, validators),
+                    source.recorded_url().to_owned(),
+                ));
+            }
+            let url = base.package_url(self, kind);
+            let validators = validators.filter(|v| v.url == url);
+            match fetch_with_retries(downloader, Some(source), &self.name, &url, validators).await {
+                Err(Error::PackageNotFound(_)) => continue,
+                Ok(Fetched::Modified(bytes, validators)) => {
+                    // Redirects may have led somewhere else but it's this url that's requested
+                    // next time
+                    let validators = Validators { url, ..validators };
+                    return Ok((
+                        Fetched::Modified(bytes, validators),
+                        source.recorded_url().to_owned(),
+                    ));
+                }
+                result => return result.map(|fetched| (fetched, source.recorded_url().to_owned())),
+            }
+        }
+        match &self.locked_source {
+            Some(locked) => Err(Error::LockedSourceMismatch(
+                self.name.clone(),
+                locked.clone(),
+            )),
+            None => Err(Error::PackageNotFound(self.name.clone())),
+        }
+    }
+}
+
+/// The `nuget_dependencies` table of an editable manifest, which is created if it's missing
+fn dependency_table(
+    document: &mut toml_edit::DocumentMut,
+) -> Result<&mut dyn toml_edit::TableLike, Error> {
+    ["package", "metadata", "nuget_dependencies"]
+        .iter()
+        .try_fold(
+            document.as_table_mut() as &mut dyn toml_edit::TableLike,
+            |table, key| {
+                table
+                    .entry(key)
+                    .or_insert_with(|| {
+                        let mut table = toml_edit::Table::new();
+                        // Only the innermost table gets a header
+                        table.set_implicit(true);
+                        toml_edit::Item::Table(table)
+                    })
+                    .as_table_like_mut()
+                    .ok_or_else(|| Error::MalformedManifest(format!("{} is not a table", key)))
+            },
+        )
+}
+
+/// The key of a dependency in the `nuget_dependencies` table, which is matched case
+/// insensitively like package ids
+fn find_dependency(deps: &dyn toml_edit::TableLike, name: &str) -> Option<String> {
+    deps.iter()
+        .map(|(key, _)| key.to_owned())
+        .find(|key| key.eq_ignore_ascii_case(name))
+}
+
+/// Finds the highest version matching a floating version among those published in the
+/// sources, or only in `locked_source` if given, or the lowest with `--prefer-lowest`
+async fn resolve_floating(
+    downloader: &Downloader,
+    name: &str,
+    version: &str,
+    locked_source: Option<&str>,
+) -> Result<String, Error> {
+    let floating = match FloatingVersion::parse(version) {
+        Some(f) => f,
+        None => return Ok(version.to_owned()),
+    };
+    let versions: Vec<String> = published_versions(downloader, name, locked_source)
+        .await?
+        .into_iter()
+        .flat_map(|(_, versions)| versions)
+        .collect();
+    floating
+        .pick(
+            versions.iter().map(String::as_str),
+            downloader.prefer_lowest,
+        )
+        .map(str::to_owned)
+        .ok_or_else(|| Error::NoMatchingVersion(name.to_owned(), version.to_owned()))
+}
+
+/// The versions of a package published in each source along with the source's url, or only
+/// in `locked_source` if given
+///
+/// With `--as-of` only versions published by then are listed. Unlisted versions can't be
+/// told apart from those published long ago because nuget.org sets their publish date to
+/// 1900, so they're left out. With `--registration-snapshot` the versions are taken from
+/// the snapshot without contacting the sources.
+async fn published_versions(
+    downloader: &Downloader,
+    name: &str,
+    locked_source: Option<&str>,
+) -> Result<Vec<(String, Vec<String>)>, Error> {
+    if let Some(snapshot) = &downloader.snapshot {
+        return snapshot.published(name, locked_source);
+    }
+    let mut published = Vec::new();
+    for source in &downloader.sources {
+        if locked_source.is_some_and(|l| !source.is(l)) {
+            continue;
+        }
+        let mut versions = Vec::new();
+        let base = source
+            .package_base(
+                &downloader.client,
+                downloader.cache.as_ref(),
+                downloader.refresh_index,
+            )
+            .await?;
+        let dated = downloader.as_of.is_some();
+        if let PackageBase::Local(dir) = &base {
+            if dated {
+                return Err(Error::UndatedSource(dir.display().to_string()));
+            }
+            published.push((source.recorded_url().to_owned(), base.local_versions(name)));
+            continue;
+        }
+        let mut pages = vec![base.versions_url(name, dated)?];
+        while let Some(url) = pages.pop() {
+            match download_index(downloader, source, name, &url).await {
+                Ok(bytes) => {
+                    let (page_versions, next) = base.parse_versions(&url, &bytes, dated)?;
+                    versions.extend(
+                        page_versions
+                            .into_iter()
+                            .filter(|v| downloader.published_in_time(v))
+                            .map(|v| v.version),
+                    );
+                    pages.extend(next);
+                }
+                Err(Error::PackageNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        published.push((source.recorded_url().to_owned(), versions));
+    }
+    Ok(published)
+}
+
+/// Downloads a feed metadata document such as a page of published versions, or takes it from
+/// the cache if it was downloaded recently, unless `--refresh-index` was passed
+async fn download_index(
+    downloader: &Downloader,
+    source: &Source,
+    name: &str,
+    url: &str,
+) -> Result<Vec<u8>, Error> {
+    let cache = downloader.cache.as_ref();
+    if let Some(bytes) = cache
+        .filter(|_| !downloader.refresh_index)
+        .and_then(|c| c.get_index(url))
+    {
+        return Ok(bytes);
+    }
+    let bytes = download_with_retries(downloader, Some(source), name, url).await?;
+    if let Some(cache) = cache {
+        if let Err(e) = cache.put_index(url, &bytes) {
+            warn(format!("Could not cache {}: {}", url, e));
+        }
+    }
+    Ok(bytes)
+}
+
+/// The error for a package none of the sources have, suggesting packages with similar ids
+/// in case `name` is mistyped
+///
+/// The suggestions are best effort, sources that can't be searched or fail to answer are
+/// skipped.
+async fn not_found(downloader: &Downloader, name: String) -> Error {
+    let mut candidates = Vec::new();
+    for source in &downloader.sources {
+        let base = match source
+            .package_base(
+                &downloader.client,
+                downloader.cache.as_ref(),
+                downloader.refresh_index,
+            )
+            .await
+        {
+            Ok(base) => base,
+            Err(_) => continue,
+        };
+        if let PackageBase::Local(_) = base {
+            candidates.extend(base.local_ids());
+            continue;
+        }
+        let url = match base.search_url(&name) {
+            Some(url) => url,
+            None => continue,
+        };
+        if let Ok(bytes) = download_with_retries(downloader, Some(source), &name, &url).await {
+            candidates.extend(base.parse_search(&bytes));
+        }
+    }
+    let suggestions = suggest::closest(&name, &candidates);
+    if suggestions.is_empty() {
+        Error::PackageNotFound(name)
+    } else {
+        Error::PackageNotFoundSuggestions { name, suggestions }
+    }
+}
+
+/// Downloads `url`, retrying failures that may go away by themselves
+///
+/// Every retry comes out of the downloader's retry budget so a feed that's down fails the
+/// install quickly instead of each package being retried in turn.
+async fn download_with_retries(
+    downloader: &Downloader,
+    source: Option<&Source>,
+    name: &str,
+    url: &str,
+) -> Result<Vec<u8>, Error> {
+    fetch_with_retries(downloader, source, name, url, None)
+        .await?
+        .into_bytes()
+}
+
+/// Like `download_with_retries`, but only downloads `url` if it changed since it was served
+/// with `validators`
+async fn fetch_with_retries(
+    downloader: &Downloader,
+    source: Option<&Source>,
+    name: &str,
+    url: &str,
+    validators: Option<&Validators>,
+) -> Result<Fetched, Error> {
+    let mut attempt = 0;
+    loop {
+        let result = try_download(
+            downloader.clone(),
+            source.cloned(),
+            name.to_owned(),
+            url.to_owned(),
+            validators.cloned(),
+            5,
+        )
+        .await;
+        if let Err(Error::HttpStatus {
+            status: 429 | 503, ..
+        }) = &result
+        {
+            downloader.concurrency.back_off();
+        }
+        match result {
+            Err(e) if e.is_transient() && attempt < downloader.retries => {
+                if !downloader.take_retry() {
+                    warn(format!(
+                        "the retry budget is used up so {} isn't retried",
+                        name
+                    ));
+                    return Err(e);
+                }
+                attempt += 1;
+                warn(format!(
+                    "retrying {} ({} of {}): {}",
+                    name, attempt, downloader.retries, e
+                ));
+                tokio::time::delay_for(retry_delay(attempt)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// How long to wait before the given retry, doubling from half a second up to
+/// `MAX_RETRY_DELAY`
+fn retry_delay(attempt: u32) -> std::time::Duration {
+    let delay = std::time::Duration::from_millis(500 << attempt.saturating_sub(1).min(6));
+    delay.min(MAX_RETRY_DELAY)
+}
+
+/// The longest a download waits before being retried
+const MAX_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// The most retries `--retries` allows, so a typo can't make an install wait for hours
+const MAX_RETRIES: u32 = 10;
+
+fn parse_retries(retries: &str) -> Result<u32, String> {
+    match retries.parse() {
+        Ok(retries) if retries > MAX_RETRIES => {
+            Err(format!("retries must be at most {}", MAX_RETRIES))
+        }
+        Ok(retries) => Ok(retries),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+/// Downloads `url`, following redirects at most `recursion_amount` times
+fn try_download(
+    downloader: Downloader,
+    source: Option<Source>,
+    name: String,
+    url: String,
+    validators: Option<Validators>,
+    recursion_amount: u8,
+) -> BoxFuture<'static, Result<Fetched, Error>> {
+    async move {
+        if recursion_amount == 0 {
+            return Err(Error::TooManyRedirects(url));
+        }
+        let client = source
+            .as_ref()
+            .map_or(&downloader.client, |s| s.client(&downloader.client));
+        let mut request = client.get(&url);
+        if let Some(validators) = &validators {
+            if let Some(etag) = &validators.etag {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &validators.last_modified {
+                request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let request = match &source {
+            Some(source) => source.authorize(&url, request).await?,
+            None => request,
+        };
+        let mut res = request.send().await.map_err(network_error(&url))?;
+        let status = res.status().as_u16();
+        match classify_status(status, validators.is_some()) {
+            StatusClass::Empty => Err(Error::EmptyResponse { url, status }),
+            StatusClass::Package => {
+                let limit = downloader.max_download_size;
+                // Feeds streaming with chunked transfer encoding don't send a length
+                let expected = res.content_length();
+                match (limit, expected) {
+                    (Some(limit), Some(length)) if length > limit => {
+                        return Err(Error::DownloadTooLarge(name, limit))
+                    }
+                    _ => {}
+                }
+                let header = |name| {
+                    res.headers()
+                        .get(name)
+                        .and_then(|v: &HeaderValue| v.to_str().ok())
+                        .map(str::to_owned)
+                };
+                let validators = Validators {
+                    url: url.clone(),
+                    etag: header(reqwest::header::ETAG),
+                    last_modified: header(reqwest::header::LAST_MODIFIED),
+                };
+                let mut spool = Spool::new(&downloader.temp_dir)?;
+                while let Some(chunk) = res
+                    .chunk()
+                    .await
+                    .map_err(|e| Error::Network(url.clone(), e))?
+                {
+                    spool.write(&chunk)?;
+                    if let Some(throttle) = &downloader.throttle {
+                        throttle.consume(chunk.len()).await;
+                    }
+                    // Feeds may stream without a content length so keep checking
+                    match limit {
+                        Some(limit) if spool.written() as u64 > limit => {
+                            return Err(Error::DownloadTooLarge(name, limit))
+                        }
+                        _ => {}
+                    }
+                }
+                // A chunked response is complete once its last chunk arrived, so there's only
+                // a length to check if one was announced
+                match expected {
+                    Some(expected) if spool.written() as u64 != expected => {
+                        return Err(Error::TruncatedDownload {
+                            url,
+                            expected,
+                            received: spool.written(),
+                        })
+                    }
+                    _ => {}
+                }
+                Ok(Fetched::Modified(spool.finish()?, validators))
+            }
+            StatusClass::Redirect => {
+                // A redirect that doesn't say where to is as useless as a failed request
+                let location = match res.headers().get("Location").map(|l| l.to_str()) {
+                    Some(Ok(location)) => location.to_owned(),
+                    _ => return Err(Error::HttpStatus { url, status }),
+                };
+                try_download(
+                    downloader,
+                    source,
+                    name,
+                    location,
+                    validators,
+                    recursion_amount - 1,
+                )
+                .await
+            }
+            StatusClass::NotModified => Ok(Fetched::NotModified(url)),
+            StatusClass::NotFound => Err(Error::PackageNotFound(name)),
+            StatusClass::Failed => Err(Error::HttpStatus { url, status }),
+        }
+    }
+    .boxed()
+}
+
+/// What the status of the response to a download means
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StatusClass {
+    /// The body is the document
+    Package,
+    /// The request succeeded but the response can't have a body
+    Empty,
+    Redirect,
+    /// The copy the validators came from is still current
+    NotModified,
+    NotFound,
+    /// The download fails with the status
+    Failed,
+}
+
+/// Classifies the status of the response to a download, which is `conditional` if it was
+/// sent with validators
+///
+/// The statuses counted as success are fixed: every 2xx status, except No Content and Reset
+/// Content which never have a body, and Partial Content since ranges are never requested.
+fn classify_status(status: u16, conditional: bool) -> StatusClass {
+    match status {
+        204 | 205 => StatusClass::Empty,
+        206 => StatusClass::Failed,
+        200..=299 => StatusClass::Package,
+        302 => StatusClass::Redirect,
+        304 if conditional => StatusClass::NotModified,
+        404 => StatusClass::NotFound,
+        _ => StatusClass::Failed,
+    }
+}
+
+/// The response to a request that's conditional if there are validators
+enum Fetched {
+    /// The document, along with what identifies this copy of it
+    Modified(Vec<u8>, Validators),
+    /// The copy the validators came from, at the given url, is still current
+    NotModified(String),
+}
+
+impl Fetched {
+    /// The document, for requests that weren't conditional
+    fn into_bytes(self) -> Result<Vec<u8>, Error> {
+        match self {
+            Fetched::Modified(bytes, _) => Ok(bytes),
+            Fetched::NotModified(url) => Err(Error::HttpStatus { url, status: 304 }),
+        }
+    }
+}
+
+/// The shared state used to download packages
+#[derive(Clone)]
+struct Downloader {
+    client: reqwest::Client,
+    sources: Vec<Source>,
+    /// The maximum number of bytes a single package may be
+    max_download_size: Option<u64>,
+    /// Shared by all downloads to stay below `--max-rate`
+    throttle: Option<Arc<Throttle>>,
+    /// How many times a single download is retried
+    retries: u32,
+    /// How many retries are left for the whole install, if limited
+    retry_budget: Option<Arc<AtomicUsize>>,
+    cache: Option<Cache>,
+    /// Use cached packages without checking the source at all
+    prefer_cache: bool,
+    /// Ignore cached feed metadata
+    refresh_index: bool,
+    /// Where downloads are spooled
+    temp_dir: PathBuf,
+    /// Only resolve floating versions to versions published by then, as
+    /// `YYYY-MM-DDTHH:MM:SS` in UTC
+    as_of: Option<String>,
+    /// Resolve floating versions to their lowest match
+    prefer_lowest: bool,
+    /// The published versions to resolve floating versions against instead of the sources'
+    snapshot: Option<Arc<Snapshot>>,
+    /// Also download symbol packages
+    symbols: bool,
+    events: Events,
+    /// Limits the packages worked on at the same time, lowered when a source is overloaded
+    concurrency: Arc<Concurrency>,
+    /// The output directory whose nupkgs kept by `--no-extract` are used before the cache
+    retained: Option<PathBuf>,
+}
+
+impl Downloader {
+    /// Whether a version was published by `--as-of`, if given
+    fn published_in_time(&self, version: &ListedVersion) -> bool {
+        match (&self.as_of, &version.published) {
+            (None, _) => true,
+            (Some(as_of), Some(published)) => {
+                !published.starts_with("1900-")
+                    && published.get(..as_of.len()).unwrap_or(published) <= as_of.as_str()
+            }
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Takes a retry from the budget, returning whether there was one left
+    fn take_retry(&self) -> bool {
+        match &self.retry_budget {
+            Some(budget) => budget
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                    left.checked_sub(1)
+                })
+                .is_ok(),
+            None => true,
+        }
+    }
+}
+
+/// The winmds, dlls and docs extracted from a package
+type Contents = (Vec<Winmd>, Vec<Dll>, Vec<Doc>);
+
+/// A nupkg as it was downloaded
+struct Download {
+    bytes: Vec<u8>,
+    /// The url of the source the package came from
+    ///
+    /// There's no source for packages downloaded from their own url or taken from the cache
+    /// without asking one.
+    source: Option<String>,
+    /// Whether the package was taken from the cache instead of being downloaded
+    cached: bool,
+    /// What the source identified the package with, to be cached along with it
+    validators: Option<Validators>,
+}
+
+struct DownloadedDependency {
+    dependency: Dependency,
+    /// The url of the source the package was downloaded from, if it's known
+    source: Option<String>,
+    /// Whether the package was taken from the cache
+    cached: bool,
+    /// The size of the nupkg in bytes
+    size: usize,
+    /// The SHA-256 hash of the nupkg
+    sha256: String,
+    /// Whether the winmds were extracted into a folder per framework
+    by_framework: bool,
+    /// The license the nuspec declares
+    license: Option<License>,
+    /// The package itself, kept with `--no-extract`
+    nupkg: Option<Vec<u8>>,
+    contents: Contents,
+    symbols: Vec<Pdb>,
+    runtimes: Vec<CopiedFile>,
+    tools: Vec<CopiedFile>,
+}
+
+impl DownloadedDependency {
+    fn new(
+        dependency: Dependency,
+        download: Download,
+        symbols: Option<Vec<u8>>,
+        options: &ExtractOptions,
+        progress: Option<&Arc<Progress>>,
+    ) -> Result<Self, Error> {
+        let Download {
+            bytes,
+            source,
+            cached,
+            ..
+        } = download;
+        let by_framework = !options.frameworks.is_empty() && dependency.paths.is_empty();
+        let extraction = match progress {
+            // Nothing is read from the package but the nuspec
+            Some(_) if options.no_extract => None,
+            Some(progress) => {
+                // Every framework is read in a separate pass over the package
+                let mut passes = if by_framework {
+                    options.frameworks.len()
+                } else {
+                    1
+                };
+                passes += options.include_native_runtimes as usize + options.include_tools as usize;
+                let entries =
+                    archive_len(&bytes) * passes + symbols.as_deref().map_or(0, archive_len);
+                Some(progress.extraction(&dependency.name, entries))
+            }
+            None => None,
+        };
+        let extraction = extraction.as_ref();
+        let nuspec = Nuspec::from_package(&dependency.name, &bytes)?;
+        if !nuspec.matches(&dependency.name, &dependency.version) {
+            return Err(Error::PackageMismatch {
+                requested: format!("{} {}", dependency.name, dependency.version),
+                received: format!("{} {}", nuspec.id, nuspec.version),
+            });
+        }
+        if nuspec.needs_newer_client() {
+            let error = Error::NewerClientRequired {
+                name: dependency.name.clone(),
+                version: dependency.version.clone(),
+                required: nuspec.min_client_version.clone().unwrap_or_default(),
+            };
+            if options.strict {
+                return Err(error);
+            }
+            warn(error.to_string());
+        }
+        if !nuspec.has_authors(&dependency.authors) {
+            return Err(Error::UnexpectedAuthors {
+                name: dependency.name.clone(),
+                expected: dependency.authors.clone(),
+                actual: nuspec.authors,
+            });
+        }
+        let license = nuspec.license;
+        if options.no_extract {
+            return Ok(Self {
+                dependency,
+                source,
+                cached,
+                size: bytes.len(),
+                sha256: format!("{:x}", Sha256::digest(&bytes)),
+                by_framework: false,
+                license,
+                nupkg: Some(bytes),
+                contents: (Vec::new(), Vec::new(), Vec::new()),
+                symbols: Vec::new(),
+                runtimes: Vec::new(),
+                tools: Vec::new(),
+            });
+        }
+        // Explicit paths replace the folders of every framework as well
+        let contents = if by_framework {
+            Self::read_frameworks(&dependency, &bytes, options, extraction)?
+        } else {
+            Self::read_contents(&dependency, &bytes, options, extraction)?
+        };
+        let symbols =
+            match symbols.map(|s| Self::read_symbols(&dependency, &s, options, extraction)) {
+                Some(Ok(symbols)) => symbols,
+                Some(Err(e)) => {
+                    warn(format!(
+                        "could not read the symbol package of {} {}: {}",
+                        dependency.name, dependency.version, e
+                    ));
+                    Vec::new()
+                }
+                None => Vec::new(),
+            };
+        let runtimes = if options.include_native_runtimes {
+            Self::read_folder(&dependency, &bytes, "runtimes", options, extraction)?
+        } else {
+            Vec::new()
+        };
+        let tools = if options.include_tools {
+            Self::read_folder(&dependency, &bytes, "tools", options, extraction)?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            dependency,
+            source,
+            cached,
+            size: bytes.len(),
+            sha256: format!("{:x}", Sha256::digest(&bytes)),
+            by_framework,
+            license,
+            nupkg: None,
+            contents,
+            symbols,
+            runtimes,
+            tools,
+        })
+    }
+
+    fn winmds(&self) -> &[Winmd] {
+        &self.contents.0
+    }
+
+    /// Describes the package and the files it installs for the install report
+    fn report(&self) -> PackageReport {
+        let mut files = Vec::new();
+        files.extend(
+            self.winmds()
+                .iter()
+                .map(|w| FileReport::new(Path::new(&w.name), &w.contents)),
+        );
+        files.extend(
+            self.dlls()
+                .iter()
+                .map(|d| FileReport::new(&d.name, &d.contents)),
+        );
+        files.extend(
+            self.docs()
+                .iter()
+                .map(|d| FileReport::new(Path::new(&d.name), &d.contents)),
+        );
+        files.extend(
+            self.symbols()
+                .iter()
+                .map(|p| FileReport::new(&p.path(Path::new("")), &p.contents)),
+        );
+        files.extend(
+            self.runtimes()
+                .iter()
+                .chain(self.tools())
+                .map(|f| FileReport::new(&f.name, &f.contents)),
+        );
+        if let Some(nupkg) = &self.nupkg {
+            files.push(FileReport::new(Path::new(&self.nupkg_name()), nupkg));
+        }
+        PackageReport {
+            name: self.dependency.name.clone(),
+            requested: self.dependency.requested.clone(),
+            version: self.dependency.version.clone(),
+            source: self.source.clone(),
+            cached: self.cached,
+            bytes_downloaded: if self.cached { 0 } else { self.size },
+            license: self.license.as_ref().map(License::to_string),
+            files,
+        }
+    }
+
+    fn nupkg_name(&self) -> String {
+        self.dependency.nupkg_name()
+    }
+
+    fn dlls(&self) -> &[Dll] {
+        &self.contents.1
+    }
+
+    fn docs(&self) -> &[Doc] {
+        &self.contents.2
+    }
+
+    fn symbols(&self) -> &[Pdb] {
+        &self.symbols
+    }
+
+    fn runtimes(&self) -> &[CopiedFile] {
+        &self.runtimes
+    }
+
+    fn tools(&self) -> &[CopiedFile] {
+        &self.tools
+    }
+
+    /// The number of bytes installing this dependency writes
+    fn extracted_size(&self) -> u64 {
+        let winmds = self.winmds().iter().map(|w| w.contents.len());
+        let dlls = self.dlls().iter().map(|d| d.contents.len());
+        let docs = self.docs().iter().map(|d| d.contents.len());
+        let pdbs = self.symbols().iter().map(|p| p.contents.len());
+        let copied = self.runtimes().iter().chain(self.tools());
+        let copied = copied.map(|f| f.contents.len());
+        let nupkg = self.nupkg.as_ref().map(Vec::len);
+        winmds
+            .chain(dlls)
+            .chain(docs)
+            .chain(pdbs)
+            .chain(copied)
+            .chain(nupkg)
+            .map(|l| l as u64)
+            .sum()
+    }
+
+    /// The lock file entry describing this dependency
+    fn locked(&self) -> LockedPackage {
+        let mut winmds: Vec<_> = self
+            .winmds()
+            .iter()
+            .map(|w| w.name.to_string_lossy().into_owned())
+            .collect();
+        winmds.sort();
+        LockedPackage {
+            name: self.dependency.name.clone(),
+            version: self.dependency.version.clone(),
+            source: self.source.clone(),
+            winmds,
+            sha256: Some(self.sha256.clone()),
+            frameworks: self.locked_frameworks(),
+        }
+    }
+
+    /// The winmds extracted for each framework along with their hashes, grouped by the
+    /// framework folder they're extracted into
+    fn locked_frameworks(&self) -> Vec<LockedFramework> {
+        if !self.by_framework {
+            return Vec::new();
+        }
+        let mut frameworks: Vec<LockedFramework> = Vec::new();
+        for winmd in self.winmds() {
+            let name = winmd.name.to_string_lossy();
+            let (framework, name) = match name.split_once('/') {
+                Some(parts) => parts,
+                None => continue,
+            };
+            let file = LockedFile {
+                name: name.to_owned(),
+                sha256: format!("{:x}", Sha256::digest(&winmd.contents)),
+            };
+            match frameworks.iter_mut().find(|f| f.framework == framework) {
+                Some(f) => f.winmds.push(file),
+                None => frameworks.push(LockedFramework {
+                    framework: framework.to_owned(),
+                    winmds: vec![file],
+                }),
+            }
+        }
+        for framework in &mut frameworks {
+            framework.winmds.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+        frameworks.sort_by(|a, b| a.framework.cmp(&b.framework));
+        frameworks
+    }
+
+    /// Checks that the nupkg is the one the lock file recorded
+    ///
+    /// A mismatch only causes a warning if `ignore` is set. Dependencies whose version isn't
+    /// in the lock file, or which were locked without a hash, have nothing to be checked against.
+    fn verify_hash(&self, lockfile: &Lockfile, ignore: bool) -> Result<(), Error> {
+        let expected = match lockfile
+            .find(&self.dependency.name, &self.dependency.version)
+            .and_then(|p| p.sha256.as_deref())
+        {
+            Some(h) => h,
+            None => return Ok(()),
+        };
+        if expected == self.sha256 {
+            return Ok(());
+        }
+        if ignore {
+            warn(format!(
+                "THE INTEGRITY OF {} {} WAS NOT VERIFIED: its SHA-256 hash is {} but nuget.lock recorded {}, which --ignore-hash ignores",
+                self.dependency.name, self.dependency.version, self.sha256, expected
+            ));
+            return Ok(());
+        }
+        Err(Error::HashMismatch {
+            name: self.dependency.name.clone(),
+            version: self.dependency.version.clone(),
+            expected: expected.to_owned(),
+            actual: self.sha256.clone(),
+        })
+    }
+
+    /// Checks that the same winmds were extracted as when the lock file was written
+    ///
+    /// Dependencies whose version isn't in the lock file are new and have nothing to be checked against.
+    fn verify_against(&self, lockfile: &Lockfile) -> Result<(), Error> {
+        let locked = match lockfile.find(&self.dependency.name, &self.dependency.version) {
+            Some(l) => l,
+            None => return Ok(()),
+        };
+        let actual = self.locked();
+        // Frameworks recorded by an earlier install that weren't requested this time, or the
+        // other way around, have nothing to be compared with
+        if !locked.frameworks.is_empty() && !actual.frameworks.is_empty() {
+            for expected in &locked.frameworks {
+                let found = match actual
+                    .frameworks
+                    .iter()
+                    .find(|f| f.framework == expected.framework)
+                {
+                    Some(f) => f,
+                    None => continue,
+                };
+                let mut differing: Vec<String> = expected
+                    .winmds
+                    .iter()
+                    .filter(|e| !found.winmds.contains(e))
+                    .chain(found.winmds.iter().filter(|f| !expected.winmds.contains(f)))
+                    .map(|f| f.name.clone())
+                    .collect();
+                differing.sort();
+                differing.dedup();
+                if !differing.is_empty() {
+                    return Err(Error::FrameworkMismatch {
+                        name: actual.name,
+                        version: actual.version,
+                        framework: expected.framework.clone(),
+                        differing,
+                    });
+                }
+            }
+            return Ok(());
+        }
+        if actual.winmds != locked.winmds {
+            return Err(Error::ExtractionMismatch {
+                name: actual.name,
+                version: actual.version,
+                expected: locked.winmds.clone(),
+                actual: actual.winmds,
+            });
+        }
+        Ok(())
+    }
+
+    fn read_contents(
+        dependency: &Dependency,
+        zip: &[u8],
+        options: &ExtractOptions,
+        extraction: Option<&Extraction>,
+    ) -> Result<Contents, Error> {
+        let reader = std::io::Cursor::new(zip);
+        let mut zip =
+            zip::ZipArchive::new(reader).map_err(|e| Error::Zip(dependency.name.clone(), e))?;
+        // Winmds and docs along with the precedence of the folder they were found in
+        let mut winmds = Vec::new();
+        let mut dlls = Vec::new();
+        let mut docs = Vec::new();
+        for i in 0..zip.len() {
+            if let Some(extraction) = extraction {
+                extraction.advance();
+            }
+            let mut file = match archive_entry(&mut zip, dependency, i, options)? {
+                Some(f) => f,
+                None => continue,
+            };
+            let archive_path = file.name().replace('\\', "/");
+            if dependency.is_excluded(&archive_path) {
+                continue;
+            }
+            // With explicit paths nothing else is extracted, whichever folder it's in
+            let listed = dependency.listed_precedence(&archive_path);
+            if !dependency.paths.is_empty() && listed.is_none() {
+                continue;
+            }
+            let path = file.sanitized_name();
+            if is_symlink(&file) {
+                drop(file);
+                file = match Self::follow_symlink(&mut zip, dependency, &archive_path, options)? {
+                    Some(f) => f,
+                    None => continue,
+                };
+            }
+            match path.extension() {
+                Some(e)
+                    if e == "winmd"
+                        || (e == "xml" && (options.include_docs || listed.is_some())) =>
+                {
+                    let is_doc = e == "xml";
+                    let location = match (listed, path.file_name()) {
+                        (Some(p), Some(name)) => Some((p, name.to_owned())),
+                        (Some(_), None) => None,
+                        (None, _) => options.winmd_location(&archive_path, &path),
+                    };
+                    let (precedence, name) = match location {
+                        Some(l) => l,
+                        None => continue,
+                    };
+                    if !options.allows_size(dependency, &archive_path, file.size()) {
+                        continue;
+                    }
+                    let contents = match read_entry(&mut file, dependency, &archive_path, options)?
+                    {
+                        Some(c) => c,
+                        None => continue,
+                    };
+                    let modified = Self::modified(&file, options);
+                    if is_doc {
+                        docs.push((
+                            precedence,
+                            Doc {
+                                name,
+                                contents,
+                                modified,
+                            },
+                        ));
+                    } else {
+                        winmds.push((
+                            precedence,
+                            Winmd {
+                                name,
+                                contents,
+                                modified,
+                            },
+                        ));
+                    }
+                }
+                Some(e) if e == "dll" && (path.starts_with("runtimes") || listed.is_some()) => {
+                    let name: PathBuf = if path.starts_with("runtimes") {
+                        path.components()
+                            .filter(|c| match c {
+                                std::path::Component::Normal(p) => {
+                                    *p != "native" && *p != "runtimes"
+                                }
+                                // Sanitized names have nothing but normal components
+                                _ => false,
+                            })
+                            .collect()
+                    } else {
+                        match path.file_name() {
+                            Some(name) => name.into(),
+                            None => continue,
+                        }
+                    };
+                    if !options.allows_size(dependency, &archive_path, file.size()) {
+                        continue;
+                    }
+                    let contents = match read_entry(&mut file, dependency, &archive_path, options)?
+                    {
+                        Some(c) => c,
+                        None => continue,
+                    };
+                    let modified = Self::modified(&file, options);
+                    dlls.push(Dll {
+                        name,
+                        contents,
+                        modified,
+                    });
+                }
+                _ if listed.is_some() && !file.is_dir() => warn(format!(
+                    "{} of {} matches its paths but only winmd, dll and xml files are extracted",
+                    archive_path, dependency.name
+                )),
+                _ => {}
+            }
+        }
+
+        let winmds = highest_precedence(winmds, |w| &w.name);
+        let docs = highest_precedence(docs, |d| &d.name);
+        // Archives can list their entries in any order so sort to keep output reproducible
+        dlls.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok((winmds, dlls, docs))
+    }
+
+    /// Reads the winmds and docs of every requested framework into a folder named after it
+    fn read_frameworks(
+        dependency: &Dependency,
+        zip: &[u8],
+        options: &ExtractOptions,
+        extraction: Option<&Extraction>,
+    ) -> Result<Contents, Error> {
+        let (mut all_winmds, mut all_dlls, mut all_docs) = (Vec::new(), Vec::new(), Vec::new());
+        for (i, framework) in options.frameworks.iter().enumerate() {
+            let framework = &if is_glob(framework) {
+                let available = Self::available_frameworks(&dependency.name, zip)?;
+                // Without a match nothing is found in the pattern's folder, which is reported below
+                newest_framework(framework, &available)?.unwrap_or_else(|| framework.clone())
+            } else {
+                framework.clone()
+            };
+            let options = ExtractOptions {
+                winmd_folders: vec![
+                    format!("lib/{}", framework),
+                    format!("contentFiles/any/{}", framework),
+                ],
+                ..options.clone()
+            };
+            let (winmds, dlls, docs) = Self::read_contents(dependency, zip, &options, extraction)?;
+            if winmds.is_empty() {
+                let error = Error::MissingFramework {
+                    name: dependency.name.clone(),
+                    framework: framework.clone(),
+                    available: Self::available_frameworks(&dependency.name, zip)?,
+                };
+                if options.strict {
+                    return Err(error);
+                }
+                warn(error.to_string());
+            }
+            // Always use `/` so lock files are the same on every platform
+            let in_framework = |name: &OsString| {
+                OsString::from(format!("{}/{}", framework, name.to_string_lossy()))
+            };
+            all_winmds.extend(winmds.into_iter().map(|w| Winmd {
+                name: in_framework(&w.name),
+                ..w
+            }));
+            all_docs.extend(docs.into_iter().map(|d| Doc {
+                name: in_framework(&d.name),
+                ..d
+            }));
+            // Dlls don't depend on the framework so reading them once is enough
+            if i == 0 {
+                all_dlls = dlls;
+            }
+        }
+        Ok((all_winmds, all_dlls, all_docs))
+    }
+
+    /// The frameworks a package has winmds for, i.e. folders of `lib` directly containing one
+    fn available_frameworks(name: &str, zip: &[u8]) -> Result<Vec<String>, Error> {
+        let reader = std::io::Cursor::new(zip);
+        let zip = zip::ZipArchive::new(reader).map_err(|e| Error::Zip(name.to_owned(), e))?;
+        let mut frameworks: Vec<String> = zip
+            .file_names()
+            .filter_map(|name| {
+                let name = name.replace('\\', "/");
+                let mut parts = name.split('/');
+                match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                    (Some(lib), Some(framework), Some(file), None)
+                        if lib.eq_ignore_ascii_case("lib") && file.ends_with(".winmd") =>
+                    {
+                        Some(framework.to_owned())
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        frameworks.sort();
+        frameworks.dedup();
+        Ok(frameworks)
+    }
+
+    /// Reads the PDBs out of a symbol package, keeping their paths inside the package
+    fn read_symbols(
+        dependency: &Dependency,
+        zip: &[u8],
+        options: &ExtractOptions,
+        extraction: Option<&Extraction>,
+    ) -> Result<Vec<Pdb>, Error> {
+        let reader = std::io::Cursor::new(zip);
+        let mut zip = zip::ZipArchive::new(reader)
+            .map_err(|e| Error::Zip(format!("{}.symbols", dependency.name), e))?;
+        let mut pdbs = Vec::new();
+        for i in 0..zip.len() {
+            if let Some(extraction) = extraction {
+                extraction.advance();
+            }
+            let mut file = match archive_entry(&mut zip, dependency, i, options)? {
+                Some(f) => f,
+                None => continue,
+            };
+            let archive_path = file.name().replace('\\', "/");
+            if dependency.is_excluded(&archive_path) {
+                continue;
+            }
+            let name = file.sanitized_name();
+            if name.extension() != Some(OsStr::new("pdb")) {
+                continue;
+            }
+            if is_symlink(&file) {
+                drop(file);
+                file = match Self::follow_symlink(&mut zip, dependency, &archive_path, options)? {
+                    Some(f) => f,
+                    None => continue,
+                };
+            }
+            if !options.allows_size(dependency, &archive_path, file.size()) {
+                continue;
+            }
+            let contents = match read_entry(&mut file, dependency, &archive_path, options)? {
+                Some(c) => c,
+                None => continue,
+            };
+            let modified = Self::modified(&file, options);
+            pdbs.push(Pdb {
+                name,
+                contents,
+                modified,
+            });
+        }
+        pdbs.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(pdbs)
+    }
+
+    /// Reads every file in the given top level folder of the package, keeping their paths
+    /// inside the package
+    fn read_folder(
+        dependency: &Dependency,
+        zip: &[u8],
+        folder: &str,
+        options: &ExtractOptions,
+        extraction: Option<&Extraction>,
+    ) -> Result<Vec<CopiedFile>, Error> {
+        let reader = std::io::Cursor::new(zip);
+        let mut zip =
+            zip::ZipArchive::new(reader).map_err(|e| Error::Zip(dependency.name.clone(), e))?;
+        let mut files = Vec::new();
+        for i in 0..zip.len() {
+            if let Some(extraction) = extraction {
+                extraction.advance();
+            }
+            let mut file = match archive_entry(&mut zip, dependency, i, options)? {
+                Some(f) => f,
+                None => continue,
+            };
+            let archive_path = file.name().replace('\\', "/");
+            if file.is_dir() || dependency.is_excluded(&archive_path) {
+                continue;
+            }
+            let name = file.sanitized_name();
+            if !name.starts_with(folder) {
+                continue;
+            }
+            if is_symlink(&file) {
+                drop(file);
+                file = match Self::follow_symlink(&mut zip, dependency, &archive_path, options)? {
+                    Some(f) => f,
+                    None => continue,
+                };
+            }
+            if !options.allows_size(dependency, &archive_path, file.size()) {
+                continue;
+            }
+            let contents = match read_entry(&mut file, dependency, &archive_path, options)? {
+                Some(c) => c,
+                None => continue,
+            };
+            let modified = Self::modified(&file, options);
+            // Tools are meant to be run but packages made on Windows don't record modes
+            let executable = file.unix_mode().is_some_and(|m| m & 0o111 != 0)
+                || (folder == "tools" && name.extension().is_none_or(|e| e == "sh"));
+            files.push(CopiedFile {
+                name,
+                contents,
+                modified,
+                executable,
+            });
+        }
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(files)
+    }
+
+    /// The entry a symlink entry of the archive points to, following symlinks to symlinks
+    ///
+    /// Symlinks are skipped with a warning unless `--resolve-symlinks` was passed, and always
+    /// if they point outside of the package or to something that isn't in it. A symlink whose
+    /// target can't be read fails like any other file that can't be extracted.
+    fn follow_symlink<'a>(
+        zip: &'a mut zip::ZipArchive<std::io::Cursor<&[u8]>>,
+        dependency: &Dependency,
+        link: &str,
+        options: &ExtractOptions,
+    ) -> Result<Option<zip::read::ZipFile<'a>>, Error> {
+        if !options.resolve_symlinks {
+            warn(format!(
+                "skipping {} of {} which is a symlink, pass --resolve-symlinks to extract what it points to",
+                link, dependency.name
+            ));
+            return Ok(None);
+        }
+        // Enough for any sensible package while stopping symlinks pointing at each other
+        const MAX_HOPS: usize = 8;
+        let mut path = link.to_owned();
+        for _ in 0..MAX_HOPS {
+            let target = match zip.by_name(&path) {
+                Ok(mut file) if is_symlink(&file) => {
+                    match read_entry(&mut file, dependency, &path, options)? {
+                        Some(target) => String::from_utf8_lossy(&target).into_owned(),
+                        None => return Ok(None),
+                    }
+                }
+                Ok(_) => break,
+                Err(_) => {
+                    warn(format!(
+                        "skipping the symlink {} of {} which points to {} but the package has no such file",
+                        link, dependency.name, path
+                    ));
+                    return Ok(None);
+                }
+            };
+            path = match resolve_symlink(&path, &target) {
+                Some(p) => p,
+                None => {
+                    warn(format!(
+                        "skipping the symlink {} of {} which points outside of the package to {}",
+                        link, dependency.name, target
+                    ));
+                    return Ok(None);
+                }
+            };
+        }
+        match zip.by_name(&path) {
+            Ok(file) if !is_symlink(&file) && !file.is_dir() => Ok(Some(file)),
+            _ => {
+                warn(format!(
+                    "skipping the symlink {} of {} which doesn't lead to a file",
+                    link, dependency.name
+                ));
+                Ok(None)
+            }
+        }
+    }
+
+    /// The time extracted files should be marked as modified at
+    fn modified(file: &zip::read::ZipFile, options: &ExtractOptions) -> Option<SystemTime> {
+        if options.preserve_mtime {
+            zip_time(file.last_modified())
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether an archive entry is a symlink, which only archives made on Unix can contain
+fn is_symlink(file: &zip::read::ZipFile) -> bool {
+    const S_IFMT: u32 = 0o170000;
+    const S_IFLNK: u32 = 0o120000;
+    file.unix_mode()
+        .is_some_and(|mode| mode & S_IFMT == S_IFLNK)
+}
+
+/// The archive path a symlink at `link` pointing to `target` refers to, unless it points
+/// outside of the archive
+fn resolve_symlink(link: &str, target: &str) -> Option<String> {
+    if target.starts_with('/') || target.contains(['\\', ':']) {
+        return None;
+    }
+    let mut parts: Vec<&str> = link.split('/').collect();
+    // Targets are relative to the folder the symlink is in
+    parts.pop();
+    for part in target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            part => parts.push(part),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+/// Whether a framework given to --framework is a glob rather than a single framework
+fn is_glob(framework: &str) -> bool {
+    framework.contains(['*', '?', '['])
+}
+
+/// The highest version among the frameworks matching the glob `pattern`
+///
+/// Numbers in framework names are compared by their value so `uap10.0.18362` is newer
+/// than `uap10.0.17763`, and `uap10.0.10240` newer than `uap10.0.9600`.
+fn newest_framework(pattern: &str, available: &[String]) -> Result<Option<String>, Error> {
+    let glob = Pattern::new(pattern).map_err(|_| Error::InvalidPattern(pattern.to_owned()))?;
+    let options = glob::MatchOptions {
+        case_sensitive: false,
+        ..Default::default()
+    };
+    Ok(available
+        .iter()
+        .filter(|f| glob.matches_with(f, options))
+        .max_by(|a, b| framework_key(a).cmp(&framework_key(b)))
+        .cloned())
+}
+
+/// A package found in the output directory, printed by `list-installed --json`
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct InstalledPackage {
+    /// The name of the package's directory
+    name: String,
+    /// The version the lock file records, if it has the package
+    version: Option<String>,
+    /// How many files are in the package's directory, including its subdirectories
+    files: usize,
+    /// The combined size of the files in bytes
+    size: u64,
+}
+
+/// The target frameworks of a package, printed by `frameworks --json`
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct FrameworksListing {
+    name: String,
+    version: String,
+    frameworks: Vec<FrameworkFolders>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct FrameworkFolders {
+    framework: String,
+    /// The folders the framework has files in, any of `lib`, `ref` and `runtimes`
+    folders: Vec<&'static str>,
+}
+
+/// The target frameworks found in a package with the given entries, along with the folders
+/// they have files in, ordered like `newest_framework` orders them
+///
+/// Frameworks are the folders of `lib/` and `ref/` and of `runtimes/<rid>/lib/`, matched
+/// case insensitively.
+fn package_frameworks<'a>(
+    entries: impl Iterator<Item = &'a str>,
+) -> Vec<(String, Vec<&'static str>)> {
+    let mut frameworks: Vec<(String, Vec<&'static str>)> = Vec::new();
+    for entry in entries {
+        let entry = entry.replace('\\', "/");
+        let parts: Vec<&str> = entry.split('/').collect();
+        // Folders without any files don't count
+        if parts.last().is_none_or(|file| file.is_empty()) {
+            continue;
+        }
+        let is = |part: &str, name: &str| part.eq_ignore_ascii_case(name);
+        let (folder, framework) = match parts.as_slice() {
+            [folder, framework, _, ..] if is(folder, "lib") => ("lib", *framework),
+            [folder, framework, _, ..] if is(folder, "ref") => ("ref", *framework),
+            [folder, _, lib, framework, _, ..] if is(folder, "runtimes") && is(lib, "lib") => {
+                ("runtimes", *framework)
+            }
+            _ => continue,
+        };
+        match frameworks
+            .iter_mut()
+            .find(|(f, _)| f.eq_ignore_ascii_case(framework))
+        {
+            Some((_, folders)) if !folders.contains(&folder) => folders.push(folder),
+            Some(_) => {}
+            None => frameworks.push((framework.to_owned(), vec![folder])),
+        }
+    }
+    for (_, folders) in &mut frameworks {
+        folders.sort();
+    }
+    frameworks.sort_by(|(a, _), (b, _)| framework_key(a).cmp(&framework_key(b)));
+    frameworks
+}
+
+/// Splits a framework name into runs of digits, compared by value, and everything else
+fn framework_key(framework: &str) -> Vec<(String, u64)> {
+    let mut key = Vec::new();
+    let mut rest = framework;
+    while !rest.is_empty() {
+        let text_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (text, after) = rest.split_at(text_len);
+        let digits_len = after
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(after.len());
+        let (digits, after) = after.split_at(digits_len);
+        key.push((text.to_ascii_lowercase(), digits.parse().unwrap_or(0)));
+        rest = after;
+    }
+    key
+}
+
+/// The entry of the archive at `index`, or `None` if it's corrupt and
+/// `--continue-on-extract-error` was passed
+fn archive_entry<'a>(
+    zip: &'a mut zip::ZipArchive<std::io::Cursor<&[u8]>>,
+    dependency: &Dependency,
+    index: usize,
+    options: &ExtractOptions,
+) -> Result<Option<zip::read::ZipFile<'a>>, Error> {
+    match zip.by_index(index) {
+        Ok(file) => {
+            // Names not flagged as UTF-8 are read as CP437, so only names claiming to be UTF-8
+            // can be invalid, in which case the invalid bytes are replaced
+            if std::str::from_utf8(file.name_raw()).is_err() && file.name().contains('\u{FFFD}') {
+                warn(format!(
+                    "the name of entry {} of {} {} is not valid UTF-8, extracting it as {}",
+                    index,
+                    dependency.name,
+                    dependency.version,
+                    file.name()
+                ));
+            }
+            Ok(Some(file))
+        }
+        Err(e) if options.continue_on_extract_error => {
+            warn(format!(
+                "skipping entry {} of {} {} which can't be read: {}",
+                index, dependency.name, dependency.version, e
+            ));
+            Ok(None)
+        }
+        Err(e) => Err(Error::CorruptEntry {
+            name: dependency.name.clone(),
+            index,
+            error: e,
+        }),
+    }
+}
+
+/// The contents of an archive entry, or `None` if they can't be read and
+/// `--continue-on-extract-error` was passed
+fn read_entry(
+    file: &mut zip::read::ZipFile,
+    dependency: &Dependency,
+    archive_path: &str,
+    options: &ExtractOptions,
+) -> Result<Option<Vec<u8>>, Error> {
+    let mut contents = Vec::with_capacity(file.size() as usize);
+    match file.read_to_end(&mut contents) {
+        Ok(_) => Ok(Some(contents)),
+        Err(e) if options.continue_on_extract_error => {
+            warn(format!(
+                "skipping {} of {} {} which can't be extracted: {}",
+                archive_path, dependency.name, dependency.version, e
+            ));
+            Ok(None)
+        }
+        Err(e) => Err(Error::ExtractFailed {
+            name: dependency.name.clone(),
+            path: archive_path.to_owned(),
+            error: e,
+        }),
+    }
+}
+
+/// The number of entries in a zip archive, or 0 if it can't be read
+///
+/// Unreadable archives fail with a proper error as soon as they're extracted.
+fn archive_len(zip: &[u8]) -> usize {
+    zip::ZipArchive::new(std::io::Cursor::new(zip)).map_or(0, |z| z.len())
+}
+
+/// Keeps one file per name out of files found in folders of different precedence
+///
+/// When several folders contain a file with the same name the one from the folder with
+/// the highest precedence wins. The result is sorted by name.
+fn highest_precedence<T>(mut files: Vec<(usize, T)>, name: impl Fn(&T) -> &OsString) -> Vec<T> {
+    files.sort_by_key(|(precedence, _)| *precedence);
+    let mut seen = std::collections::HashSet::new();
+    let mut files: Vec<_> = files
+        .into_iter()
+        .map(|(_, file)| file)
+        .filter(|file| seen.insert(name(file).clone()))
+        .collect();
+    // Archives can list their entries in any order so sort to keep output reproducible
+    files.sort_by(|a, b| name(a).cmp(name(b)));
+    files
+}
+
+/// Converts the timestamp of a zip entry into a `SystemTime`
+///
+/// Zip timestamps have no time zone so they're treated as UTC.
+fn zip_time(time: zip::DateTime) -> Option<SystemTime> {
+    // Days since the unix epoch of the civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let (year, month, day) = (
+        i64::from(time.year()),
+        i64::from(time.month()),
+        i64::from(time.day()),
+    );
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    let seconds = days * 86_400
+        + i64::from(time.hour()) * 3_600
+        + i64::from(time.minute()) * 60
+        + i64::from(time.second());
+    let seconds = u64::try_from(seconds).ok()?;
+    Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(seconds))
+}
+
+/// Folders searched for winmds, from highest to lowest precedence
+const DEFAULT_WINMD_FOLDERS: &[&str] = &[
+    "lib/uap10.0",
+    "build/native",
+    "buildTransitive/native",
+    "build",
+    "buildTransitive",
+    "contentFiles/any/uap10.0",
+    "content",
+];
+
+/// Whether winmds are searched for in the subfolders of a winmd folder as well
+///
+/// Content is copied into projects as it is, so its folders are structured like the
+/// project wants them to be.
+fn is_content_folder(folder: &str) -> bool {
+    let top = folder.split('/').next().unwrap_or_default();
+    top.eq_ignore_ascii_case("content") || top.eq_ignore_ascii_case("contentFiles")
+}
+
+/// Settings controlling which files are extracted from packages
+#[derive(Debug, Clone)]
+struct ExtractOptions {
+    /// Archive folders searched for winmds, from highest to lowest precedence
+    winmd_folders: Vec<String>,
+    /// Target frameworks whose winmds are each extracted into their own folder
+    frameworks: Vec<String>,
+    /// Whether a framework without winmds in a package, or a package requiring a newer
+    /// NuGet client, is an error rather than a warning
+    strict: bool,
+    /// Give extracted files the modification time recorded in the package
+    preserve_mtime: bool,
+    /// Also extract xml documentation files from the winmd folders
+    include_docs: bool,
+    /// Files larger than this many bytes are skipped
+    max_file_size: Option<u64>,
+    /// Files smaller than this many bytes are skipped
+    min_file_size: Option<u64>,
+    /// Symlinks in packages are replaced by what they point to instead of being skipped
+    resolve_symlinks: bool,
+    /// Copy the package's runtimes folder as it is
+    include_native_runtimes: bool,
+    /// Copy the package's tools folder as it is
+    include_tools: bool,
+    /// Files that can't be extracted are skipped instead of failing the install
+    continue_on_extract_error: bool,
+    /// Keep the package itself instead of reading any files from it
+    no_extract: bool,
+}
+
+impl ExtractOptions {
+    /// The precedence of the winmd folder a file is in along with the name it's extracted
+    /// under, if winmds are extracted from its folder
+    ///
+    /// `archive_path` is the file's path in the archive and `path` the sanitized one. Files
+    /// in content folders keep their path below the folder, always separated by `/`, and
+    /// files in other folders only their name.
+    fn winmd_location(&self, archive_path: &str, path: &Path) -> Option<(usize, OsString)> {
+        let folder = archive_path.rsplit_once('/').map_or("", |(f, _)| f);
+        self.winmd_folders.iter().enumerate().find_map(|(i, f)| {
+            let f = f.trim_matches('/');
+            if f.eq_ignore_ascii_case(folder) {
+                return Some((i, path.file_name()?.to_owned()));
+            }
+            let below = archive_path
+                .get(..f.len() + 1)
+                .is_some_and(|p| p[..f.len()].eq_ignore_ascii_case(f) && p.ends_with('/'));
+            if !is_content_folder(f) || !below {
+                return None;
+            }
+            let name: Vec<_> = path
+                .components()
+                .skip(f.split('/').count())
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect();
+            Some((i, OsString::from(name.join("/"))))
+        })
+    }
+
+    /// Whether a file of the given size is extracted, warning about it if it isn't
+    fn allows_size(&self, dependency: &Dependency, path: &str, size: u64) -> bool {
+        let limit = match (self.max_file_size, self.min_file_size) {
+            (Some(max), _) if size > max => format!("larger than --max-file-size {}", max),
+            (_, Some(min)) if size < min => format!("smaller than --min-file-size {}", min),
+            _ => return true,
+        };
+        warn(format!(
+            "skipping {} of {} which is {} bytes, {}",
+            path, dependency.name, size, limit
+        ));
+        false
+    }
+}
+
+/// Downloads and reads the packages of `deps`, working on as many at a time as the
+/// downloader's concurrency allows
+///
+/// `progress` is ticked whenever a package is done and shows how far along each package's
+/// extraction is. Pressing Ctrl-C or reaching the `deadline` cancels all downloads that are
+/// still in flight.
+///
+/// With `fail_fast` the first package that fails cancels the others, otherwise every package
+/// is attempted and all failures are reported together.
+fn download_dependencies(
+    runtime: &mut tokio::runtime::Runtime,
+    deps: Vec<Dependency>,
+    downloader: &Downloader,
+    options: &ExtractOptions,
+    progress: Option<&Arc<Progress>>,
+    deadline: Option<Deadline>,
+    fail_fast: bool,
+) -> Result<Vec<DownloadedDependency>, Error> {
+    let names: Vec<String> = deps.iter().map(|d| d.name.clone()).collect();
+    runtime.block_on(async {
+        let results = deps.into_iter().map(|dep| async move {
+            let _slot = downloader.concurrency.acquire().await;
+            let events = downloader.events;
+            let name = dep.name.clone();
+            events.emit(Event::Started {
+                package: &dep.name,
+                version: &dep.version,
+            });
+            let result = match dep.download(downloader).await {
+                Ok(download) => {
+                    events.emit(Event::Downloaded {
+                        package: &dep.name,
+                        version: &dep.version,
+                        bytes: download.bytes.len(),
+                    });
+                    let symbols = if downloader.symbols {
+                        dep.download_symbols(downloader).await
+                    } else {
+                        None
+                    };
+                    // Unzipping is CPU bound so it runs on the blocking pool where packages
+                    // are extracted in parallel with each other and the remaining downloads
+                    let options = options.clone();
+                    let progress = progress.cloned();
+                    tokio::task::spawn_blocking(move || {
+                        DownloadedDependency::new(
+                            dep,
+                            download,
+                            symbols,
+                            &options,
+                            progress.as_ref(),
+                        )
+                    })
+                    .await
+                    .unwrap_or_else(|e| Err(Error::Task(e)))
+                }
+                Err(e) => Err(e),
+            };
+            match &result {
+                Ok(_) => {
+                    downloader.concurrency.succeeded();
+                    if let Some(progress) = progress {
+                        progress.tick();
+                    }
+                }
+                Err(e) => events.emit(Event::Error {
+                    package: Some(&name),
+                    code: e.code(),
+                    message: e.to_string(),
+                }),
+            }
+            result
+        });
+
+        let downloads = async {
+            if fail_fast {
+                futures::future::try_join_all(results).await
+            } else {
+                collect_failures(names, futures::future::join_all(results).await)
+            }
+        };
+        let downloads = Box::pin(within(deadline, downloads));
+        match futures::future::select(downloads, Box::pin(ctrl_c())).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(Error::Interrupted),
+        }
+    })
+}
+
+/// The downloaded packages if every package succeeded, otherwise the failures along with
+/// the packages they happened to
+///
+/// A single failure is returned as it is so its code stays the same as with `--fail-fast`.
+fn collect_failures(
+    names: Vec<String>,
+    results: Vec<Result<DownloadedDependency, Error>>,
+) -> Result<Vec<DownloadedDependency>, Error> {
+    let mut downloaded = Vec::new();
+    let mut failures = Vec::new();
+    for (name, result) in names.into_iter().zip(results) {
+        match result {
+            Ok(dep) => downloaded.push(dep),
+            Err(e) => failures.push((name, e)),
+        }
+    }
+    match failures.len() {
+        0 => Ok(downloaded),
+        1 => Err(failures.pop().unwrap().1),
+        _ => Err(Error::PackagesFailed(failures)),
+    }
+}
+
+/// Fails if any package's license isn't allowed by `--allowed-licenses`, listing every such
+/// package when there are several
+fn check_licenses(deps: &[DownloadedDependency], policy: &LicensePolicy) -> Result<(), Error> {
+    let mut failures: Vec<(String, Error)> = deps
+        .iter()
+        .filter(|dep| !policy.allows(dep.license.as_ref()))
+        .map(|dep| {
+            let error = Error::LicenseNotAllowed {
+                name: dep.dependency.name.clone(),
+                version: dep.dependency.version.clone(),
+                license: dep.license.clone(),
+            };
+            (dep.dependency.name.clone(), error)
+        })
+        .collect();
+    match failures.len() {
+        0 => Ok(()),
+        1 => Err(failures.pop().unwrap().1),
+        _ => Err(Error::PackagesFailed(failures)),
+    }
+}
+
+/// Checks every downloaded package against the hash nuget.lock recorded, printing the
+/// outcome for each, see `--verify-only`
+///
+/// Packages without a recorded hash fail since nothing vouches for them.
+fn verify_downloads(deps: &[DownloadedDependency], lockfile: &Lockfile) -> Result<(), Error> {
+    let mut failures = Vec::new();
+    for dep in deps {
+        let Dependency { name, version, .. } = &dep.dependency;
+        let result = match lockfile.find(name, version).and_then(|p| p.sha256.as_ref()) {
+            Some(_) => dep.verify_hash(lockfile, false),
+            None => Err(Error::NoLockedHash(name.clone(), version.clone())),
+        };
+        match result {
+            Ok(()) => eprintln!("Verified {} {}", name, version),
+            Err(e) => {
+                eprintln!("FAILED {} {}: {}", name, version, e);
+                failures.push((name.clone(), e));
+            }
+        }
+    }
+    eprintln!(
+        "Verified {} of {} package(s)",
+        deps.len() - failures.len(),
+        deps.len()
+    );
+    match failures.len() {
+        0 => Ok(()),
+        1 => Err(failures.pop().unwrap().1),
+        _ => Err(Error::PackagesFailed(failures)),
+    }
+}
+
+/// Lists the failures of `Error::PackagesFailed`, one per line
+fn describe_failures(failures: &[(String, Error)]) -> String {
+    failures
+        .iter()
+        .map(|(name, e)| format!("\n  {}: error[{}]: {}", name, e.code(), e))
+        .collect()
+}
+
+/// Writes an extracted file, setting its modification time if one is given
+///
+/// With a store the file is only a link to the store's copy.
+fn write_file(
+    path: &Path,
+    contents: &[u8],
+    modified: Option<SystemTime>,
+    store: Option<&Store>,
+) -> Result<(), Error> {
+    if let Some(store) = store {
+        return store.link(path, contents, modified);
+    }
+    std::fs::write(path, contents).map_err(write_error(path))?;
+    if let Some(modified) = modified {
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .and_then(|f| f.set_modified(modified))
+            .map_err(write_error(path))?;
+    }
+    Ok(())
+}
+
+/// Creates `dir` and any missing parents
+fn create_dir(dir: &Path) -> Result<(), Error> {
+    std::fs::create_dir_all(dir).map_err(write_error(dir))
+}
+
+/// Turns an io error that happened while writing `path` into an `Error`
+fn write_error(path: &Path) -> impl FnOnce(std::io::Error) -> Error + '_ {
+    move |e| Error::WriteFailed(path.to_owned(), e)
+}
+
+fn read_error(path: &Path) -> impl FnOnce(std::io::Error) -> Error + '_ {
+    move |e| Error::ReadFailed(path.to_owned(), e)
+}
+
+/// Turns a failed request to `url` into an `Error`, telling failed TLS handshakes apart
+/// since they're usually caused by --min-tls
+fn network_error(url: &str) -> impl FnOnce(reqwest::Error) -> Error + '_ {
+    move |e| {
+        let mut cause: Option<&(dyn std::error::Error + 'static)> = Some(&e);
+        let mut handshake = false;
+        while let Some(c) = cause {
+            handshake |= c.is::<native_tls::Error>();
+            cause = c.source();
+        }
+        if handshake {
+            Error::TlsHandshake(url.to_owned(), e)
+        } else {
+            Error::Network(url.to_owned(), e)
+        }
+    }
+}
+
+/// Explains why writing `path` failed
+///
+/// Full disks and read-only filesystems are common on CI runners so they get messages
+/// saying what to fix rather than just the OS error.
+fn describe_write_error(path: &Path, error: &std::io::Error) -> String {
+    match error.kind() {
+        std::io::ErrorKind::StorageFull => {
+            format!("The disk became full while writing {}", path.display())
+        }
+        std::io::ErrorKind::ReadOnlyFilesystem => format!(
+            "Could not write {} because the filesystem is read-only",
+            path.display()
+        ),
+        _ => format!("Could not write {}: {}", path.display(), error),
+    }
+}
+
+struct Winmd {
+    name: OsString,
+    contents: Vec<u8>,
+    modified: Option<SystemTime>,
+}
 
 impl Winmd {
-    fn write(&self, dir: &Path) -> std::io::Result<()> {
-        std::fs::write(dir.join(&self.name), &self.contents)
+    fn write(&self, dir: &Path, store: Option<&Store>) -> Result<(), Error> {
+        // Winmds of a framework are in a folder named after it
+        let path = dir.join(&self.name);
+        create_dir(path.parent().unwrap())?;
+        write_file(&path, &self.contents, self.modified, store)
+    }
+}
+
+/// An xml documentation file found next to a package's winmds
+struct Doc {
+    name: OsString,
+    contents: Vec<u8>,
+    modified: Option<SystemTime>,
+}
+
+impl Doc {
+    fn write(&self, dir: &Path, store: Option<&Store>) -> Result<(), Error> {
+        let path = dir.join(&self.name);
+        create_dir(path.parent().unwrap())?;
+        write_file(&path, &self.contents, self.modified, store)
+    }
+}
+
+/// The folder of an installed package PDBs are extracted into
+const SYMBOLS_FOLDER: &str = "symbols";
+
+struct Pdb {
+    /// The path of the PDB inside the symbol package
+    name: PathBuf,
+    contents: Vec<u8>,
+    modified: Option<SystemTime>,
+}
+
+impl Pdb {
+    fn path(&self, dir: &Path) -> PathBuf {
+        dir.join(SYMBOLS_FOLDER).join(&self.name)
     }
+
+    fn write(&self, dir: &Path, store: Option<&Store>) -> Result<(), Error> {
+        let path = self.path(dir);
+        create_dir(path.parent().unwrap())?;
+        write_file(&path, &self.contents, self.modified, store)
+    }
+}
+
+/// A file of a package folder copied as it is, by --include-native-runtimes or
+/// --include-tools
+struct CopiedFile {
+    /// The path of the file inside the package, starting with the folder
+    name: PathBuf,
+    contents: Vec<u8>,
+    modified: Option<SystemTime>,
+    /// Whether the file gets the executable bit on Unix
+    executable: bool,
+}
+
+impl CopiedFile {
+    fn write(&self, dir: &Path, store: Option<&Store>) -> Result<(), Error> {
+        let path = dir.join(&self.name);
+        create_dir(path.parent().unwrap())?;
+        write_file(&path, &self.contents, self.modified, store)?;
+        if self.executable {
+            set_executable(&path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))
+        .map_err(write_error(path))
+}
+
+/// Windows has no executable bit, whether a file can be run depends on its extension
+#[cfg(not(unix))]
+fn set_executable(_: &Path) -> Result<(), Error> {
+    Ok(())
 }
 
 struct Dll {
     name: PathBuf,
     contents: Vec<u8>,
+    modified: Option<SystemTime>,
 }
 
 impl Dll {
-    fn write(&self, dir: &Path) -> std::io::Result<()> {
+    fn write(&self, dir: &Path, store: Option<&Store>) -> Result<(), Error> {
+        let path = dir.join(&self.name);
+        create_dir(path.parent().unwrap())?;
+        write_file(&path, &self.contents, self.modified, store)
+    }
+
+    /// Links the installed dll into the profile directories so it's found at runtime
+    fn link(&self, root: &Path, dir: &Path, force: bool) -> Result<(), Error> {
         let path = dir.join(&self.name);
-        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
-        if !path.exists() {
-            std::fs::write(&path, &self.contents).unwrap();
-        }
         for profile in &["debug", "release"] {
-            let profile_path = workspace_root().join("target").join(profile);
-            std::fs::create_dir_all(&profile_path).unwrap();
+            let profile_path = root.join("target").join(profile);
+            create_dir(&profile_path)?;
             let arch = self.name.parent().unwrap();
-            let dll_path = profile_path.join(&self.name.strip_prefix(&arch).unwrap());
-            if arch.as_os_str() == "win10-x64" && std::fs::read_link(&dll_path).is_err() {
-                println!("{} {:?}", dll_path.display(), dll_path.exists());
-                std::os::windows::fs::symlink_file(&path, dll_path).unwrap();
+            let dll_path = profile_path.join(self.name.strip_prefix(arch).unwrap());
+            if arch.as_os_str() != "win10-x64" {
+                continue;
+            }
+            if force && std::fs::read_link(&dll_path).is_ok() {
+                std::fs::remove_file(&dll_path).map_err(write_error(&dll_path))?;
+            }
+            if std::fs::read_link(&dll_path).is_err() {
+                std::os::windows::fs::symlink_file(&path, &dll_path)
+                    .map_err(write_error(&dll_path))?;
             }
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for a test, emptied in case an earlier run left files behind
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("cargo-nuget-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A package containing the given files
+    fn package(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            zip.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            std::io::Write::write_all(&mut zip, contents).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// Reads the winmds, dlls and docs of a package like `install` with the given flags
+    fn extract(zip: &[u8], flags: &[&str]) -> Contents {
+        let dependency = Dependency::from_spec(
+            "Test".to_owned(),
+            DependencySpec::Version("1.0.0".to_owned()),
+        )
+        .unwrap();
+        let install = Install::from_iter(std::iter::once("install").chain(flags.iter().copied()));
+        DownloadedDependency::read_contents(&dependency, zip, &install.extract_options(), None)
+            .unwrap()
+    }
+
+    fn winmd<'a>(contents: &'a Contents, name: &str) -> Option<&'a Winmd> {
+        contents.0.iter().find(|w| w.name == OsStr::new(name))
+    }
+
+    #[test]
+    fn build_native_winmds_are_extracted() {
+        let zip = package(&[
+            ("build/native/Native.winmd", b"native"),
+            ("build/native/Both.winmd", b"native"),
+            ("build/Both.winmd", b"build"),
+            ("build/native/Lib.winmd", b"native"),
+            ("lib/uap10.0/Lib.winmd", b"lib"),
+        ]);
+        let contents = extract(&zip, &[]);
+        assert_eq!(contents.0.len(), 3);
+        assert_eq!(
+            winmd(&contents, "Native.winmd").unwrap().contents,
+            b"native"
+        );
+        // build/native comes after lib/uap10.0 but before build
+        assert_eq!(winmd(&contents, "Both.winmd").unwrap().contents, b"native");
+        assert_eq!(winmd(&contents, "Lib.winmd").unwrap().contents, b"lib");
+    }
+
+    #[test]
+    fn content_files_keep_their_directories() {
+        let zip = package(&[
+            ("contentFiles/any/uap10.0/Top.winmd", b"top"),
+            (
+                "contentFiles/any/uap10.0/Windows/Foundation/Nested.winmd",
+                b"nested",
+            ),
+            ("content/Metadata/Content.winmd", b"content"),
+        ]);
+        let contents = extract(&zip, &[]);
+        assert_eq!(contents.0.len(), 3);
+        assert_eq!(winmd(&contents, "Top.winmd").unwrap().contents, b"top");
+        assert_eq!(
+            winmd(&contents, "Windows/Foundation/Nested.winmd")
+                .unwrap()
+                .contents,
+            b"nested"
+        );
+        assert_eq!(
+            winmd(&contents, "Metadata/Content.winmd").unwrap().contents,
+            b"content"
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_names_are_extracted_lossily() {
+        let mut zip = package(&[("lib/uap10.0/Caf\u{e9}.winmd", b"winmd")]);
+        // The writer flags the non-ASCII name as UTF-8, so making its bytes invalid gives a
+        // name claiming to be UTF-8 that isn't, in the local and the central header
+        for i in 0..zip.len() - 1 {
+            if zip[i..i + 2] == [0xc3, 0xa9] {
+                zip[i + 1] = 0xff;
+            }
+        }
+        let contents = extract(&zip, &[]);
+        assert_eq!(contents.0.len(), 1);
+        assert!(contents.0[0].name.to_string_lossy().contains('\u{FFFD}'));
+        assert_eq!(contents.0[0].contents, b"winmd");
+        assert!(events::warnings()
+            .iter()
+            .any(|w| w.contains("is not valid UTF-8")));
+    }
+
+    /// Answers a single request on a local port with `response`, returning the url to request
+    fn serve(response: &'static [u8]) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/test.1.0.0.nupkg", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                match stream.read(&mut buffer).unwrap() {
+                    0 => break,
+                    read => request.extend_from_slice(&buffer[..read]),
+                }
+            }
+            std::io::Write::write_all(&mut stream, response).unwrap();
+        });
+        url
+    }
+
+    /// Downloads `url` like a package
+    fn download(url: String, name: &str) -> Result<Vec<u8>, Error> {
+        let dir = temp_dir(name);
+        let args = [
+            OsStr::new("install"),
+            OsStr::new("--temp-dir"),
+            dir.as_os_str(),
+        ];
+        let install = Install::from_iter(&args);
+        let downloader = install.downloader(Config::default())?;
+        let mut runtime = new_runtime()?;
+        let fetched = runtime.block_on(try_download(
+            downloader,
+            None,
+            "Test".to_owned(),
+            url,
+            None,
+            5,
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+        fetched?.into_bytes()
+    }
+
+    #[test]
+    fn chunked_downloads_are_complete_once_the_last_chunk_arrived() {
+        let url = serve(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+              5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+        );
+        assert_eq!(download(url, "chunked").unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn downloads_with_a_length_are_complete() {
+        let url = serve(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello");
+        assert_eq!(download(url, "length").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn redirects_without_a_location_fail() {
+        let url = serve(b"HTTP/1.1 302 Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        match download(url.clone(), "redirect") {
+            Err(Error::HttpStatus {
+                url: failed,
+                status,
+            }) => {
+                assert_eq!((failed, status), (url, 302));
+            }
+            result => panic!("unexpected result {:?}", result.map(|b| b.len())),
+        }
+    }
+
+    #[test]
+    fn retry_delays_double_up_to_a_limit() {
+        let delays: Vec<_> = (1..=8).map(|a| retry_delay(a).as_millis()).collect();
+        assert_eq!(delays, [500, 1000, 2000, 4000, 8000, 16000, 30000, 30000]);
+        // Shifting by the attempt itself would overflow
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn retries_are_bounded() {
+        assert_eq!(parse_retries("0"), Ok(0));
+        assert_eq!(parse_retries("10"), Ok(10));
+        assert!(parse_retries("11").is_err());
+        assert!(parse_retries("65").is_err());
+        assert!(parse_retries("-1").is_err());
+    }
+
+    fn is_unsafe(result: Result<PathBuf, Error>) -> bool {
+        matches!(result, Err(Error::UnsafePackageDir(_)))
+    }
+
+    #[test]
+    fn package_dir_rejects_parent_dirs() {
+        let out_dir = Path::new("target/nuget");
+        assert!(is_unsafe(package_dir(out_dir, "..")));
+        assert!(is_unsafe(package_dir(out_dir, "../../etc")));
+    }
+
+    #[test]
+    fn package_dir_rejects_separators() {
+        let out_dir = Path::new("target/nuget");
+        assert!(is_unsafe(package_dir(out_dir, "a/b")));
+        assert!(is_unsafe(package_dir(out_dir, "a\\b")));
+    }
+
+    #[test]
+    fn package_dir_rejects_absolute_paths() {
+        let out_dir = Path::new("target/nuget");
+        assert!(is_unsafe(package_dir(out_dir, "/etc")));
+        assert!(is_unsafe(package_dir(out_dir, "")));
+    }
+
+    #[test]
+    fn package_dir_accepts_plain_ids() {
+        let out_dir = Path::new("target/nuget");
+        assert_eq!(
+            package_dir(out_dir, "Win2D.uwp").unwrap(),
+            out_dir.join("Win2D.uwp")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn package_dir_rejects_symlinks_leading_elsewhere() {
+        let dir = temp_dir("package-dir-symlink");
+        let out_dir = dir.join("out");
+        let elsewhere = dir.join("elsewhere");
+        std::fs::create_dir_all(&out_dir).unwrap();
+        std::fs::create_dir_all(&elsewhere).unwrap();
+        std::os::unix::fs::symlink(&elsewhere, out_dir.join("Evil")).unwrap();
+        assert!(is_unsafe(package_dir(&out_dir, "Evil")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn plain_ok_is_the_package() {
+        assert_eq!(classify_status(200, false), StatusClass::Package);
+        assert_eq!(classify_status(200, true), StatusClass::Package);
+    }
+
+    #[test]
+    fn other_successes_are_the_package() {
+        for status in &[201, 202, 203, 299] {
+            assert_eq!(classify_status(*status, false), StatusClass::Package);
+        }
+    }
+
+    #[test]
+    fn no_content_is_empty() {
+        assert_eq!(classify_status(204, false), StatusClass::Empty);
+        assert_eq!(classify_status(205, false), StatusClass::Empty);
+    }
+
+    #[test]
+    fn partial_content_fails() {
+        assert_eq!(classify_status(206, false), StatusClass::Failed);
+    }
+
+    #[test]
+    fn not_modified_needs_validators() {
+        assert_eq!(classify_status(304, true), StatusClass::NotModified);
+        assert_eq!(classify_status(304, false), StatusClass::Failed);
+    }
+
+    #[test]
+    fn other_statuses() {
+        assert_eq!(classify_status(302, false), StatusClass::Redirect);
+        assert_eq!(classify_status(404, false), StatusClass::NotFound);
+        assert_eq!(classify_status(500, false), StatusClass::Failed);
+        assert_eq!(classify_status(199, false), StatusClass::Failed);
+    }
+
+    /// Compares extracting several large packages one after another with extracting them on
+    /// the blocking pool like `install`, run with
+    /// `cargo test --release extraction_benchmark -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn extraction_benchmark() {
+        // Incompressible contents, so inflating them takes about as long as in SDK packages
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut noise = vec![0; 4 << 20];
+        for byte in &mut noise {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *byte = state as u8;
+        }
+        let names: Vec<_> = (0..16)
+            .map(|i| format!("lib/uap10.0/Large{}.winmd", i))
+            .collect();
+        let files: Vec<_> = names.iter().map(|n| (n.as_str(), &noise[..])).collect();
+        let packages: Vec<_> = (0..8).map(|_| Arc::new(package(&files))).collect();
+        let dependency = || {
+            Dependency::from_spec(
+                "Test".to_owned(),
+                DependencySpec::Version("1.0.0".to_owned()),
+            )
+            .unwrap()
+        };
+        let install = Install::from_iter(std::iter::once("install"));
+        let options = install.extract_options();
+
+        let start = std::time::Instant::now();
+        for zip in &packages {
+            DownloadedDependency::read_contents(&dependency(), zip, &options, None).unwrap();
+        }
+        let serial = start.elapsed();
+
+        let concurrency = install.concurrency();
+        let concurrency = &concurrency;
+        let mut runtime = new_runtime().unwrap();
+        let start = std::time::Instant::now();
+        runtime.block_on(async {
+            let extractions = packages.iter().map(|zip| {
+                let (dependency, zip, options) = (dependency(), zip.clone(), options.clone());
+                async move {
+                    let _slot = concurrency.acquire().await;
+                    tokio::task::spawn_blocking(move || {
+                        DownloadedDependency::read_contents(&dependency, &zip, &options, None)
+                    })
+                    .await
+                    .unwrap()
+                    .unwrap();
+                }
+            });
+            futures::future::join_all(extractions).await;
+        });
+        let parallel = start.elapsed();
+
+        eprintln!(
+            "Extracting {} packages of {} MiB: {:?} one after another, {:?} in parallel",
+            packages.len(),
+            packages[0].len() >> 20,
+            serial,
+            parallel
+        );
+    }
+}
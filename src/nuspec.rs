@@ -0,0 +1,443 @@
+use crate::Error;
+
+use std::cmp::Ordering;
+use std::io::Read;
+
+/// The newest NuGet client version whose package features are understood
+///
+/// Packages declaring a higher `minClientVersion` may rely on something that isn't
+/// extracted the way NuGet would.
+pub const SUPPORTED_CLIENT_VERSION: &str = "5.0.0";
+
+/// The metadata a package describes itself with in its `.nuspec` file
+#[derive(Debug, Clone)]
+pub struct Nuspec {
+    pub id: String,
+    pub version: String,
+    /// The comma separated `authors` of the package
+    pub authors: Vec<String>,
+    /// The oldest NuGet client the package says it can be installed with
+    pub min_client_version: Option<String>,
+    pub license: Option<License>,
+}
+
+/// How a package declares its license
+#[derive(Debug, Clone, PartialEq)]
+pub enum License {
+    /// An SPDX license expression such as `MIT OR Apache-2.0`
+    Expression(String),
+    /// The path of a license file inside the package
+    File(String),
+    /// The deprecated `licenseUrl`, for packages predating license expressions
+    Url(String),
+}
+
+impl std::fmt::Display for License {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            License::Expression(expression) => write!(f, "{}", expression),
+            License::File(path) => write!(f, "the license file {}", path),
+            License::Url(url) => write!(f, "the license at {}", url),
+        }
+    }
+}
+
+impl Nuspec {
+    /// Reads the `.nuspec` at the root of the given nupkg
+    pub fn from_package(name: &str, zip: &[u8]) -> Result<Self, Error> {
+        let invalid = |reason: &str| Error::InvalidPackage(name.to_owned(), reason.to_owned());
+        let reader = std::io::Cursor::new(zip);
+        let zip_error = |e| Error::Zip(name.to_owned(), e);
+        let mut zip = zip::ZipArchive::new(reader).map_err(zip_error)?;
+        let index = (0..zip.len())
+            .find(|&i| {
+                zip.by_index(i)
+                    .map(|f| !f.name().contains('/') && f.name().ends_with(".nuspec"))
+                    .unwrap_or(false)
+            })
+            .ok_or_else(|| invalid("it has no .nuspec file"))?;
+        let mut contents = String::new();
+        zip.by_index(index)
+            .map_err(zip_error)?
+            .read_to_string(&mut contents)
+            .map_err(|_| invalid("its .nuspec file isn't valid UTF-8"))?;
+        Self::parse(&contents).ok_or_else(|| invalid("its .nuspec file is malformed"))
+    }
+
+    fn parse(xml: &str) -> Option<Self> {
+        let doc = roxmltree::Document::parse(xml).ok()?;
+        let metadata = doc
+            .root_element()
+            .children()
+            .find(|n| n.is_element() && n.tag_name().name() == "metadata")?;
+        let text = |tag: &str| {
+            metadata
+                .children()
+                .find(|n| n.is_element() && n.tag_name().name() == tag)
+                .and_then(|n| n.text())
+                .map(|t| t.trim().to_owned())
+        };
+        let authors = text("authors")
+            .unwrap_or_default()
+            .split(',')
+            .map(|a| a.trim().to_owned())
+            .filter(|a| !a.is_empty())
+            .collect();
+        let license = metadata
+            .children()
+            .find(|n| n.is_element() && n.tag_name().name() == "license")
+            .and_then(|n| {
+                let value = n.text()?.trim().to_owned();
+                match n.attribute("type") {
+                    Some("expression") => Some(License::Expression(value)),
+                    Some("file") => Some(License::File(value)),
+                    _ => None,
+                }
+            })
+            .or_else(|| text("licenseUrl").map(License::Url));
+        Some(Self {
+            id: text("id")?,
+            version: text("version")?,
+            authors,
+            min_client_version: metadata
+                .attribute("minClientVersion")
+                .map(|v| v.trim().to_owned()),
+            license,
+        })
+    }
+
+    /// Whether this is the given package id and version
+    ///
+    /// Ids are compared case insensitively and versions after normalization, as NuGet does.
+    pub fn matches(&self, id: &str, version: &str) -> bool {
+        self.id.eq_ignore_ascii_case(id)
+            && normalize_version(&self.version) == normalize_version(version)
+    }
+
+    /// Whether the package needs a newer NuGet client than `SUPPORTED_CLIENT_VERSION`
+    pub fn needs_newer_client(&self) -> bool {
+        self.min_client_version
+            .as_deref()
+            .is_some_and(|v| compare_versions(v, SUPPORTED_CLIENT_VERSION) == Ordering::Greater)
+    }
+
+    /// Whether every one of the expected authors is an author of the package, ignoring case
+    pub fn has_authors(&self, expected: &[String]) -> bool {
+        expected
+            .iter()
+            .all(|e| self.authors.iter().any(|a| a.eq_ignore_ascii_case(e)))
+    }
+}
+
+/// Normalizes a NuGet version so equivalent versions compare equal
+///
+/// Build metadata is dropped, missing parts are filled with zeros (`1.0` is `1.0.0`) and a
+/// zero fourth part is removed (`1.0.0.0` is `1.0.0`).
+pub fn normalize_version(version: &str) -> String {
+    let version = version.trim();
+    let version = version.split('+').next().unwrap_or(version);
+    let (release, prerelease) = match version.find('-') {
+        Some(i) => (&version[..i], Some(&version[i + 1..])),
+        None => (version, None),
+    };
+    let mut parts: Vec<String> = release
+        .split('.')
+        .map(|p| match p.parse::<u64>() {
+            Ok(n) => n.to_string(),
+            Err(_) => p.to_owned(),
+        })
+        .collect();
+    while parts.len() < 3 {
+        parts.push("0".to_owned());
+    }
+    if parts.len() == 4 && parts[3] == "0" {
+        parts.pop();
+    }
+    let mut normalized = parts.join(".");
+    if let Some(prerelease) = prerelease {
+        normalized.push('-');
+        normalized.push_str(&prerelease.to_lowercase());
+    }
+    normalized
+}
+
+/// Orders NuGet versions, with prereleases before the release they lead up to
+///
+/// Prerelease labels are compared part by part like SemVer does, numeric parts numerically
+/// and others case insensitively.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (a_release, a_prerelease) = split_version(a);
+    let (b_release, b_prerelease) = split_version(b);
+    a_release
+        .cmp(&b_release)
+        .then_with(|| match (a_prerelease, b_prerelease) {
+            (None, None) => Ordering::Equal,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(_), None) => Ordering::Less,
+            (Some(a), Some(b)) => {
+                let a: Vec<String> = a.split('.').map(str::to_lowercase).collect();
+                let b: Vec<String> = b.split('.').map(str::to_lowercase).collect();
+                a.iter()
+                    .zip(&b)
+                    .map(|(a, b)| match (a.parse::<u64>(), b.parse::<u64>()) {
+                        (Ok(a), Ok(b)) => a.cmp(&b),
+                        (Ok(_), Err(_)) => Ordering::Less,
+                        (Err(_), Ok(_)) => Ordering::Greater,
+                        (Err(_), Err(_)) => a.cmp(b),
+                    })
+                    .find(|o| *o != Ordering::Equal)
+                    .unwrap_or_else(|| a.len().cmp(&b.len()))
+            }
+        })
+}
+
+/// The numeric parts of a version, padded to four, and its prerelease label if any
+fn split_version(version: &str) -> (Vec<u64>, Option<&str>) {
+    let version = version.trim();
+    let version = version.split('+').next().unwrap_or(version);
+    let (release, prerelease) = match version.split_once('-') {
+        Some((release, prerelease)) => (release, Some(prerelease)),
+        None => (version, None),
+    };
+    let mut parts: Vec<u64> = release.split('.').map(|p| p.parse().unwrap_or(0)).collect();
+    parts.resize(4.max(parts.len()), 0);
+    (parts, prerelease)
+}
+
+/// A NuGet floating version which stands for the highest published version matching it
+///
+/// `1.*` and `1.2.*` match stable versions starting with the given parts and `*` any stable
+/// version. `1.2.3-*` matches 1.2.3 and its prereleases, `1.2.3-beta*` only its prereleases
+/// whose label starts with `beta`. Prereleases are matched by the floating parts as well with
+/// `1.2.*-*` or `*-*`.
+///
+/// `~1.2` is a shorthand for `1.2.*`, and `~1.2.3` for the versions of `1.2.*` from 1.2.3 on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FloatingVersion {
+    /// The numeric parts every matching version starts with
+    release: Vec<u64>,
+    /// The numeric parts every matching version is at least, for `~1.2.3`
+    minimum: Vec<u64>,
+    /// Whether versions may have more numeric parts than `release`, i.e. it ends with `*`
+    floating_release: bool,
+    /// The start of the prerelease labels that match, if prereleases match at all
+    prerelease: Option<String>,
+}
+
+impl FloatingVersion {
+    /// Parses a floating version, returning `None` for versions that don't float
+    pub fn parse(version: &str) -> Option<Self> {
+        let version = version.trim();
+        if let Some(tilde) = version.strip_prefix('~') {
+            return Self::parse_tilde(tilde);
+        }
+        let (release, prerelease) = match version.split_once('-') {
+            Some((release, prerelease)) => (release, Some(prerelease)),
+            None => (version, None),
+        };
+        let prerelease = match prerelease {
+            Some(p) => Some(p.strip_suffix('*')?.to_lowercase()),
+            None => None,
+        };
+        let (release, floating_release) = match release.strip_suffix('*') {
+            Some("") => ("", true),
+            Some(fixed) => (fixed.strip_suffix('.')?, true),
+            None => (release, false),
+        };
+        if !floating_release && prerelease.is_none() {
+            return None;
+        }
+        let release = if release.is_empty() {
+            Vec::new()
+        } else {
+            release
+                .split('.')
+                .map(|p| p.parse().ok())
+                .collect::<Option<Vec<u64>>>()?
+        };
+        let max_parts = if floating_release { 3 } else { 4 };
+        if release.len() > max_parts || (!floating_release && release.is_empty()) {
+            return None;
+        }
+        Some(Self {
+            release,
+            minimum: Vec::new(),
+            floating_release,
+            prerelease,
+        })
+    }
+
+    /// Parses what follows the `~` of a tilde version, which floats the parts after the minor
+    /// version and may take prereleases with a `-*` suffix
+    fn parse_tilde(version: &str) -> Option<Self> {
+        let (release, prerelease) = match version.split_once('-') {
+            Some((release, prerelease)) => (release, Some(prerelease)),
+            None => (version, None),
+        };
+        let prerelease = match prerelease {
+            Some(p) => Some(p.strip_suffix('*')?.to_lowercase()),
+            None => None,
+        };
+        let minimum = release
+            .split('.')
+            .map(|p| p.parse().ok())
+            .collect::<Option<Vec<u64>>>()?;
+        if minimum.is_empty() || minimum.len() > 3 {
+            return None;
+        }
+        Some(Self {
+            release: minimum.iter().take(2).copied().collect(),
+            minimum,
+            floating_release: true,
+            prerelease,
+        })
+    }
+
+    pub fn matches(&self, version: &str) -> bool {
+        let (release, prerelease) = split_version(version);
+        let release_matches = if self.floating_release {
+            release.starts_with(&self.release)
+        } else {
+            let mut fixed = self.release.clone();
+            fixed.resize(release.len(), 0);
+            release == fixed
+        };
+        let mut minimum = self.minimum.clone();
+        minimum.resize(release.len(), 0);
+        release_matches
+            && release >= minimum
+            && match (prerelease, &self.prerelease) {
+                (None, None) => true,
+                // A release counts as having an empty prerelease label
+                (label, Some(start)) => label
+                    .unwrap_or_default()
+                    .to_lowercase()
+                    .starts_with(start.as_str()),
+                (Some(_), None) => false,
+            }
+    }
+
+    /// The highest of the given versions that matches
+    pub fn highest<'a>(&self, versions: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+        versions
+            .into_iter()
+            .filter(|v| self.matches(v))
+            .max_by(|a, b| compare_versions(a, b))
+    }
+
+    /// The lowest of the given versions that matches
+    pub fn lowest<'a>(&self, versions: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+        versions
+            .into_iter()
+            .filter(|v| self.matches(v))
+            .min_by(|a, b| compare_versions(a, b))
+    }
+
+    /// The highest of the given versions that matches, or the lowest if `lowest` is set
+    pub fn pick<'a>(
+        &self,
+        versions: impl IntoIterator<Item = &'a str>,
+        lowest: bool,
+    ) -> Option<&'a str> {
+        if lowest {
+            self.lowest(versions)
+        } else {
+            self.highest(versions)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn floating_versions_match() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("1.*", "1.0.0", true),
+            ("1.*", "1.5.2", true),
+            ("1.*", "2.0.0", false),
+            ("1.*", "1.2.0-beta", false),
+            ("1.2.*-*", "1.2.0", true),
+            ("1.2.*-*", "1.2.5-beta", true),
+            ("1.2.*-*", "1.3.0-beta", false),
+            ("1.2.3-beta*", "1.2.3-beta", true),
+            ("1.2.3-beta*", "1.2.3-BETA.2", true),
+            ("1.2.3-beta*", "1.2.3-alpha", false),
+            ("1.2.3-beta*", "1.2.3", false),
+            ("1.2.3-beta*", "1.2.4-beta", false),
+            ("1.2.3-*", "1.2.3", true),
+            ("*", "0.1.0", true),
+            ("*", "10.0.0", true),
+            ("*", "1.0.0-rc", false),
+            ("*-*", "1.0.0-rc", true),
+            ("~1.2", "1.2.0", true),
+            ("~1.2", "1.2.9", true),
+            ("~1.2", "1.3.0", false),
+            ("~1.2", "1.1.9", false),
+            ("~1.2.3", "1.2.3", true),
+            ("~1.2.3", "1.2.10", true),
+            ("~1.2.3", "1.2.2", false),
+            ("~1.2.3", "1.3.0", false),
+            ("~1.2.3", "1.2.4-beta", false),
+            ("~1.2.3-*", "1.2.4-beta", true),
+        ];
+        for (floating, version, matches) in cases {
+            let parsed = FloatingVersion::parse(floating).unwrap();
+            assert_eq!(
+                parsed.matches(version),
+                *matches,
+                "{} matching {}",
+                floating,
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn versions_that_dont_float() {
+        let cases = [
+            "1.2.3",
+            "1.2.3.4",
+            "1.2.3-beta",
+            "1.*.3",
+            "a.*",
+            "1.2.3.4.*",
+            "~",
+            "~1.2.3.4",
+            "~1.x",
+        ];
+        for version in &cases {
+            assert_eq!(FloatingVersion::parse(version), None, "{}", version);
+        }
+    }
+
+    #[test]
+    fn highest_and_lowest_matches() {
+        let versions = ["1.0.0", "1.2.0", "1.10.0", "2.0.0", "1.11.0-beta"];
+        let floating = FloatingVersion::parse("1.*").unwrap();
+        assert_eq!(floating.highest(versions.iter().copied()), Some("1.10.0"));
+        assert_eq!(floating.pick(versions.iter().copied(), true), Some("1.0.0"));
+        let floating = FloatingVersion::parse("3.*").unwrap();
+        assert_eq!(floating.highest(versions.iter().copied()), None);
+    }
+
+    #[test]
+    fn version_order() {
+        let cases = [
+            ("1.0.0-alpha", "1.0.0", Ordering::Less),
+            ("1.0.0-alpha", "1.0.0-beta", Ordering::Less),
+            ("1.0.0-beta.2", "1.0.0-beta.11", Ordering::Less),
+            ("1.0.0-alpha", "1.0.0-alpha.1", Ordering::Less),
+            ("1.0.0-1", "1.0.0-alpha", Ordering::Less),
+            ("1.0.0-RC", "1.0.0-rc", Ordering::Equal),
+            ("1.0", "1.0.0.0", Ordering::Equal),
+            ("1.0.0+build", "1.0.0", Ordering::Equal),
+            ("1.9.0", "1.10.0", Ordering::Less),
+            ("2.0.0-beta", "1.9.9", Ordering::Greater),
+        ];
+        for (a, b, order) in &cases {
+            assert_eq!(compare_versions(a, b), *order, "{} compared to {}", a, b);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use crate::events::warnings;
+use crate::{write_error, Error};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::path::Path;
+use std::time::SystemTime;
+
+/// The version of the report's format, increased whenever fields change meaning or go away
+const SCHEMA_VERSION: u32 = 1;
+
+/// A record of everything an install did, written with `--report`
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Report {
+    schema_version: u32,
+    /// When the install started and finished, in RFC 3339 format
+    started_at: String,
+    finished_at: String,
+    environment: Environment,
+    packages: Vec<PackageReport>,
+    warnings: Vec<String>,
+}
+
+impl Report {
+    /// Creates the report of an install that started at `started` and just finished
+    pub fn new(
+        started: SystemTime,
+        environment: Environment,
+        packages: Vec<PackageReport>,
+    ) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            started_at: humantime::format_rfc3339_seconds(started).to_string(),
+            finished_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            environment,
+            packages,
+            warnings: warnings(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        // Serializing these types can't fail
+        let contents = serde_json::to_string_pretty(self).unwrap();
+        std::fs::write(path, contents).map_err(write_error(path))
+    }
+}
+
+/// The conditions an install ran under, so reports of installs that turned out differently
+/// can be compared
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Environment {
+    /// The version of cargo-nuget that did the install
+    pub tool_version: &'static str,
+    /// The urls of the sources packages were looked up in, in order
+    pub sources: Vec<String>,
+    /// Whether packages could be taken from the cache
+    pub cache: bool,
+    /// Whether cached packages were used without asking their source, with `--prefer-cache`
+    pub prefer_cache: bool,
+    /// Whether versions recorded in the lock file were kept, with `--locked`
+    pub locked: bool,
+    /// The SHA-256 hash of the manifest the dependencies were read from
+    pub manifest_sha256: String,
+}
+
+/// What was installed for a single dependency
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct PackageReport {
+    pub name: String,
+    /// The version as written in the manifest, which may be a floating version
+    pub requested: String,
+    pub version: String,
+    /// The url of the source the package was downloaded from, if it's known
+    pub source: Option<String>,
+    /// Whether the package was taken from the cache
+    pub cached: bool,
+    /// How many bytes were downloaded, 0 for cached packages
+    pub bytes_downloaded: usize,
+    /// The license the nuspec declares, an SPDX expression or where to find the license
+    pub license: Option<String>,
+    pub files: Vec<FileReport>,
+}
+
+/// A file written to the package's directory
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct FileReport {
+    /// The path relative to the package's directory, always separated by `/`
+    pub path: String,
+    pub size: usize,
+    pub sha256: String,
+}
+
+impl FileReport {
+    pub fn new(path: &Path, contents: &[u8]) -> Self {
+        let path = path
+            .iter()
+            .map(|c| c.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        Self {
+            path,
+            size: contents.len(),
+            sha256: format!("{:x}", Sha256::digest(contents)),
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::nuspec::License;
+use crate::{write_error, Error};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use std::path::Path;
+use std::time::SystemTime;
+
+/// The version of the CycloneDX specification the SBOM follows
+const SPEC_VERSION: &str = "1.4";
+
+/// A CycloneDX software bill of materials of the installed packages, written with `--sbom`
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Sbom {
+    bom_format: &'static str,
+    spec_version: &'static str,
+    /// Increased by tools that modify the SBOM, so always 1 for a new one
+    version: u32,
+    metadata: Metadata,
+    components: Vec<Component>,
+}
+
+impl Sbom {
+    pub fn new(components: Vec<Component>) -> Self {
+        Self {
+            bom_format: "CycloneDX",
+            spec_version: SPEC_VERSION,
+            version: 1,
+            metadata: Metadata {
+                timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+                tools: vec![Tool {
+                    name: env!("CARGO_PKG_NAME"),
+                    version: env!("CARGO_PKG_VERSION"),
+                }],
+            },
+            components,
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        // Serializing these types can't fail
+        let contents = serde_json::to_string_pretty(self).unwrap();
+        std::fs::write(path, contents).map_err(write_error(path))
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct Metadata {
+    timestamp: String,
+    tools: Vec<Tool>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct Tool {
+    name: &'static str,
+    version: &'static str,
+}
+
+/// A single installed package
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Component {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(rename = "bom-ref")]
+    bom_ref: String,
+    name: String,
+    version: String,
+    /// The package url, e.g. `pkg:nuget/Microsoft.Foo@1.0.0`
+    purl: String,
+    hashes: Vec<Hash>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    licenses: Vec<LicenseChoice>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    external_references: Vec<ExternalReference>,
+}
+
+impl Component {
+    /// Describes a package with the SHA-256 hash of its nupkg and the license its nuspec
+    /// declares, downloaded from `source` if it's known
+    pub fn new(
+        name: &str,
+        version: &str,
+        sha256: &str,
+        license: Option<&License>,
+        source: Option<&str>,
+    ) -> Self {
+        let purl = format!("pkg:nuget/{}@{}", name, version);
+        let licenses = match license {
+            Some(License::Expression(expression)) => vec![LicenseChoice::Expression {
+                expression: expression.clone(),
+            }],
+            Some(License::File(path)) => vec![LicenseChoice::License {
+                license: LicenseInfo {
+                    name: Some(format!("See {} in the package", path)),
+                    url: None,
+                },
+            }],
+            Some(License::Url(url)) => vec![LicenseChoice::License {
+                license: LicenseInfo {
+                    name: None,
+                    url: Some(url.clone()),
+                },
+            }],
+            None => Vec::new(),
+        };
+        Self {
+            kind: "library",
+            bom_ref: purl.clone(),
+            name: name.to_owned(),
+            version: version.to_owned(),
+            purl,
+            hashes: vec![Hash {
+                alg: "SHA-256",
+                content: sha256.to_owned(),
+            }],
+            licenses,
+            external_references: source
+                .map(|url| ExternalReference {
+                    kind: "distribution",
+                    url: url.to_owned(),
+                })
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct Hash {
+    alg: &'static str,
+    content: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(untagged)]
+enum LicenseChoice {
+    Expression { expression: String },
+    License { license: LicenseInfo },
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct LicenseInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct ExternalReference {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    url: String,
+}
@@ -0,0 +1,59 @@
+use crate::events::Event;
+use crate::report::Report;
+use crate::sbom::Sbom;
+use crate::{FrameworksListing, InstalledPackage};
+
+use schemars::schema_for;
+
+use std::str::FromStr;
+
+/// A machine readable output whose JSON Schema `json-schema` prints
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SchemaKind {
+    /// The install report written with `--report`
+    Report,
+    /// The CycloneDX SBOM written with `--sbom`
+    Sbom,
+    /// A single line printed with `--output json-lines`
+    Events,
+    /// The listing printed by `frameworks --json`
+    Frameworks,
+    /// The listing printed by `list-installed --json`
+    Installed,
+}
+
+impl SchemaKind {
+    pub const VARIANTS: &'static [&'static str] =
+        &["report", "sbom", "events", "frameworks", "installed"];
+}
+
+impl FromStr for SchemaKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "report" => Ok(SchemaKind::Report),
+            "sbom" => Ok(SchemaKind::Sbom),
+            "events" => Ok(SchemaKind::Events),
+            "frameworks" => Ok(SchemaKind::Frameworks),
+            "installed" => Ok(SchemaKind::Installed),
+            _ => Err(format!("unknown schema `{}`", s)),
+        }
+    }
+}
+
+/// Prints the JSON Schema of an output to stdout
+///
+/// The schemas are generated from the same types the outputs are serialized from, so they
+/// can't drift apart.
+pub fn print(kind: SchemaKind) {
+    let schema = match kind {
+        SchemaKind::Report => schema_for!(Report),
+        SchemaKind::Sbom => schema_for!(Sbom),
+        SchemaKind::Events => schema_for!(Event),
+        SchemaKind::Frameworks => schema_for!(FrameworksListing),
+        SchemaKind::Installed => schema_for!(Vec<InstalledPackage>),
+    };
+    // Serializing a schema can't fail
+    println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+}
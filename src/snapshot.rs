@@ -0,0 +1,71 @@
+use crate::{read_error, write_error, Error};
+
+use serde::{Deserialize, Serialize};
+
+use std::path::{Path, PathBuf};
+
+const HEADER: &str = "# This file is automatically generated by cargo nuget export-snapshot.\n\
+                      # It is not intended for manual editing.\n";
+
+/// The versions the sources published of the dependencies at one point in time, so floating
+/// versions can be resolved against them later without contacting the sources
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    #[serde(default, rename = "package")]
+    pub packages: Vec<SnapshotPackage>,
+    /// Where the snapshot was read from, for error messages
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+/// The versions of a package published in a single source
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotPackage {
+    pub name: String,
+    /// The url of the source
+    pub source: String,
+    pub versions: Vec<String>,
+}
+
+impl Snapshot {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path).map_err(read_error(path))?;
+        let snapshot: Self =
+            toml::from_str(&contents).map_err(|_| Error::MalformedSnapshot(path.to_owned()))?;
+        Ok(Self {
+            path: path.to_owned(),
+            ..snapshot
+        })
+    }
+
+    pub fn save(&mut self, path: &Path) -> Result<(), Error> {
+        self.packages.sort_by(|a, b| a.name.cmp(&b.name));
+        let contents = toml::to_string(self).map_err(|e| Error::Serialize("snapshot", e))?;
+        std::fs::write(path, format!("{}{}", HEADER, contents)).map_err(write_error(path))
+    }
+
+    /// The versions of a package published in each source along with the source's url, or
+    /// only in `locked_source` if given, like `published_versions` lists them
+    ///
+    /// Package ids are matched case insensitively.
+    pub fn published(
+        &self,
+        name: &str,
+        locked_source: Option<&str>,
+    ) -> Result<Vec<(String, Vec<String>)>, Error> {
+        let packages: Vec<_> = self
+            .packages
+            .iter()
+            .filter(|p| p.name.eq_ignore_ascii_case(name))
+            .collect();
+        if packages.is_empty() {
+            return Err(Error::NotInSnapshot(name.to_owned(), self.path.clone()));
+        }
+        let same_source = |a: &str, b: &str| a.trim_end_matches('/') == b.trim_end_matches('/');
+        Ok(packages
+            .into_iter()
+            .filter(|p| locked_source.is_none_or(|l| same_source(&p.source, l)))
+            .map(|p| (p.source.clone(), p.versions.clone()))
+            .collect())
+    }
+}
@@ -0,0 +1,1104 @@
+use crate::cache::Cache;
+use crate::events::warn;
+use crate::{network_error, Dependency, Error};
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, ClientBuilder, Proxy, RequestBuilder, Url};
+use serde::Deserialize;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The feed used when no source is given
+pub const DEFAULT_SOURCE: &str = "https://www.nuget.org/api/v2";
+
+/// The host serving GitHub Packages NuGet feeds
+const GITHUB_PACKAGES_HOST: &str = "nuget.pkg.github.com";
+
+/// A source as configured in the `sources` list of `[package.metadata.nuget]`
+///
+/// The url, api key and header values can be given as `env:NAME` to read them from the
+/// environment variable `NAME` so secrets don't have to be checked in. Urls can also embed
+/// variables as `${NAME}`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+    pub url: String,
+    pub api_key: Option<String>,
+    /// Extra headers sent with every request to the source
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Urls used for resources of a v3 feed instead of those its service index lists, by
+    /// resource type (`PackageBaseAddress`, `RegistrationsBaseUrl` or `SearchQueryService`)
+    #[serde(default)]
+    pub resources: BTreeMap<String, String>,
+    /// The proxy requests to the source go through instead of the one of the environment,
+    /// or `none` to connect directly
+    pub proxy: Option<String>,
+    /// Urls of mirrors serving the same packages as `url`, which are tried in the order of
+    /// how quickly they respond
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+}
+
+/// How long a mirror has to answer the probe before it's considered down
+const MIRROR_PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How a source with a proxy of its own connects
+#[derive(Debug, Clone)]
+enum SourceProxy {
+    /// Directly, ignoring proxies set in the environment
+    Direct,
+    /// Through the proxy at this url, unless the host is in `NO_PROXY`
+    Url(Url),
+}
+
+/// The resource types of a v3 service index that are used, each with the versions
+/// understood from most to least preferred
+///
+/// Only the 3.6.0 registrations list SemVer 2.0.0 versions.
+const RESOURCE_TYPES: &[(&str, &[&str])] = &[
+    ("PackageBaseAddress", &["3.0.0"]),
+    (
+        "RegistrationsBaseUrl",
+        &["3.6.0", "Versioned", "3.4.0", "3.0.0-rc", "3.0.0-beta"],
+    ),
+    ("SearchQueryService", &["3.5.0", "3.0.0-rc", "3.0.0-beta"]),
+];
+
+/// A NuGet feed packages can be downloaded from
+#[derive(Clone)]
+pub struct Source {
+    url: String,
+    /// The url as written in the manifest, before `env:` and `${NAME}` are resolved
+    ///
+    /// It's what lock files, snapshots and reports record, so they work in every
+    /// environment the manifest does and never contain secrets interpolated into the url.
+    configured_url: String,
+    api_key: Option<String>,
+    headers: HeaderMap,
+    /// Resource urls overriding those of the service index, by resource type
+    resources: BTreeMap<String, String>,
+    /// The package base, resolved the first time a package is downloaded from the source
+    base: Arc<futures::lock::Mutex<Option<PackageBase>>>,
+    /// The command asked for a token when the source has no api key
+    credential_helper: Option<String>,
+    /// The token the credential helper gave, once it has been asked
+    helper_token: Arc<futures::lock::Mutex<Option<Option<String>>>>,
+    proxy: Option<SourceProxy>,
+    /// The client used for the source instead of the shared one, for sources with a proxy
+    /// of their own
+    client: Option<Client>,
+    /// Urls of mirrors of the source as configured and resolved, see `by_health`
+    mirrors: Vec<(String, String)>,
+}
+
+impl Source {
+    /// Creates a source, falling back to `GITHUB_TOKEN` as the api key for GitHub Packages feeds
+    pub fn new(url: String, api_key: Option<String>) -> Self {
+        let api_key =
+            api_key.or_else(|| github_api_key(&url, || std::env::var("GITHUB_TOKEN").ok()));
+        Self {
+            configured_url: url.clone(),
+            url,
+            api_key,
+            headers: HeaderMap::new(),
+            resources: BTreeMap::new(),
+            base: Default::default(),
+            credential_helper: None,
+            helper_token: Default::default(),
+            proxy: None,
+            client: None,
+            mirrors: Vec::new(),
+        }
+    }
+
+    pub fn from_config(config: SourceConfig) -> Result<Self, Error> {
+        let url = interpolate_env(&resolve_env(config.url.clone())?)?;
+        let api_key = config.api_key.map(resolve_env).transpose()?;
+        let mut source = Self::new(url, api_key);
+        source.configured_url = config.url;
+        for (name, value) in config.headers {
+            let value = resolve_env(value)?;
+            let (name, value) = parse_header_parts(&name, &value).map_err(Error::InvalidHeader)?;
+            source.add_header(name, value);
+        }
+        for (kind, url) in config.resources {
+            if !RESOURCE_TYPES.iter().any(|(k, _)| *k == kind) {
+                return Err(Error::UnknownResourceType(kind));
+            }
+            source
+                .resources
+                .insert(kind, interpolate_env(&resolve_env(url)?)?);
+        }
+        source.mirrors = config
+            .mirrors
+            .into_iter()
+            .map(|m| Ok((m.clone(), interpolate_env(&resolve_env(m)?)?)))
+            .collect::<Result<_, Error>>()?;
+        if let Some(proxy) = config.proxy {
+            let proxy = interpolate_env(&resolve_env(proxy)?)?;
+            source.proxy = Some(if proxy == "none" {
+                SourceProxy::Direct
+            } else {
+                SourceProxy::Url(Url::parse(&proxy).map_err(|_| Error::InvalidProxy(proxy))?)
+            });
+        }
+        Ok(source)
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The url to record for packages from the source, see `configured_url`
+    pub fn recorded_url(&self) -> &str {
+        &self.configured_url
+    }
+
+    pub fn has_api_key(&self) -> bool {
+        self.api_key.is_some()
+    }
+
+    pub fn set_api_key(&mut self, api_key: String) {
+        self.api_key = Some(api_key);
+    }
+
+    pub fn has_mirrors(&self) -> bool {
+        !self.mirrors.is_empty()
+    }
+
+    /// The source and each of its mirrors as separate sources, the fastest to respond first
+    ///
+    /// Every one is probed once by fetching its url. Those that fail or don't answer within
+    /// `MIRROR_PROBE_TIMEOUT` are warned about and tried last, in the order they're
+    /// configured.
+    pub async fn by_health(self, shared: &Client) -> Vec<Source> {
+        let mut candidates = vec![self.clone()];
+        candidates.extend(self.mirrors.iter().map(|(configured, url)| Source {
+            url: url.clone(),
+            configured_url: configured.clone(),
+            base: Default::default(),
+            // The credential helper is asked for each mirror since it's given the url
+            helper_token: Default::default(),
+            mirrors: Vec::new(),
+            ..self.clone()
+        }));
+        let probes = candidates.iter().map(|source| source.probe(shared));
+        let latencies = futures::future::join_all(probes).await;
+        let mut ranked: Vec<(Option<std::time::Duration>, usize, Source)> = Vec::new();
+        for (i, (source, latency)) in candidates.into_iter().zip(latencies).enumerate() {
+            let latency = match latency {
+                Ok(latency) => Some(latency),
+                Err(reason) => {
+                    warn(format!(
+                        "the mirror {} is skipped unless the others fail: {}",
+                        source.url, reason
+                    ));
+                    None
+                }
+            };
+            ranked.push((latency, i, source));
+        }
+        // Sources that answered come first, then the rest in the configured order
+        ranked.sort_by_key(|(latency, i, _)| (latency.is_none(), *latency, *i));
+        ranked.into_iter().map(|(_, _, source)| source).collect()
+    }
+
+    /// How long the source takes to answer a request for its url
+    async fn probe(&self, shared: &Client) -> Result<std::time::Duration, String> {
+        let request = self
+            .authorize(&self.url, self.client(shared).get(&self.url))
+            .await
+            .map_err(|e| e.to_string())?;
+        let started = std::time::Instant::now();
+        match tokio::time::timeout(MIRROR_PROBE_TIMEOUT, request.send()).await {
+            Ok(Ok(res)) if res.status().is_success() => Ok(started.elapsed()),
+            Ok(Ok(res)) => Err(format!("it answered with status {}", res.status().as_u16())),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!(
+                "it didn't answer within {} seconds",
+                MIRROR_PROBE_TIMEOUT.as_secs()
+            )),
+        }
+    }
+
+    /// Whether requests to the source need a client of their own, see `set_client`
+    pub fn has_proxy(&self) -> bool {
+        self.proxy.is_some()
+    }
+
+    /// Sets up a client for the source's proxy, out of a builder configured like the shared
+    /// client
+    pub fn set_client(&mut self, builder: ClientBuilder) -> Result<(), Error> {
+        let builder = match &self.proxy {
+            Some(SourceProxy::Direct) => builder.no_proxy(),
+            Some(SourceProxy::Url(proxy)) => {
+                let proxy = proxy.clone();
+                let no_proxy = std::env::var("NO_PROXY")
+                    .or_else(|_| std::env::var("no_proxy"))
+                    .unwrap_or_default();
+                builder.proxy(Proxy::custom(move |url| {
+                    let bypass = url.host_str().is_some_and(|h| bypasses_proxy(&no_proxy, h));
+                    Some(proxy.clone()).filter(|_| !bypass)
+                }))
+            }
+            None => return Ok(()),
+        };
+        self.client = Some(builder.build().map_err(Error::Client)?);
+        Ok(())
+    }
+
+    /// The client requests to the source are sent with, `shared` unless the source has a
+    /// proxy of its own
+    pub fn client<'a>(&'a self, shared: &'a Client) -> &'a Client {
+        self.client.as_ref().unwrap_or(shared)
+    }
+
+    pub fn set_credential_helper(&mut self, command: String) {
+        self.credential_helper = Some(command);
+    }
+
+    pub fn add_header(&mut self, name: HeaderName, value: HeaderValue) {
+        self.headers.append(name, value);
+    }
+
+    /// Whether this is the source with the given url, as recorded or resolved
+    pub fn is(&self, url: &str) -> bool {
+        let url = url.trim_end_matches('/');
+        self.configured_url.trim_end_matches('/') == url || self.url.trim_end_matches('/') == url
+    }
+
+    /// Whether the url is on the same host as the source
+    pub fn same_host(&self, url: &str) -> bool {
+        match (Url::parse(&self.url), Url::parse(url)) {
+            (Ok(source), Ok(url)) => source.host_str() == url.host_str(),
+            _ => false,
+        }
+    }
+
+    /// Whether the source is a v3 feed described by a service index
+    ///
+    /// Sources overriding resources of the service index are v3 feeds as well.
+    pub fn is_v3(&self) -> bool {
+        self.url.ends_with("index.json") || !self.resources.is_empty()
+    }
+
+    /// The directory of a local feed, for sources given as a path or `file://` url instead
+    /// of an http(s) url
+    pub fn local_dir(&self) -> Option<PathBuf> {
+        match Url::parse(&self.url) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => None,
+            Ok(url) if url.scheme() == "file" => url.to_file_path().ok(),
+            // Windows paths like `C:\packages` parse as urls with a one letter scheme
+            _ => Some(PathBuf::from(&self.url)),
+        }
+    }
+
+    /// Makes a relative local feed relative to `base` instead of the current directory
+    pub fn resolve_relative(&mut self, base: &Path) {
+        if let Some(dir) = self.local_dir().filter(|d| d.is_relative()) {
+            self.url = base.join(dir).to_string_lossy().into_owned();
+        }
+    }
+
+    /// Adds the source's credentials and headers to a request if it targets the source's host
+    ///
+    /// Neither are ever sent to other hosts (e.g. a CDN the feed redirects to).
+    pub async fn authorize(
+        &self,
+        url: &str,
+        request: RequestBuilder,
+    ) -> Result<RequestBuilder, Error> {
+        if !self.same_host(url) {
+            return Ok(request);
+        }
+        let request = request.headers(self.headers.clone());
+        let key = match &self.api_key {
+            Some(key) => Some(key.clone()),
+            None => self.helper_token().await?,
+        };
+        Ok(match key {
+            // GitHub Packages ignores the user name and only checks that the password
+            // is a valid personal access token
+            Some(key) => request.basic_auth("cargo-nuget", Some(key)),
+            None => request,
+        })
+    }
+
+    /// The token the credential helper gives for the source, which is only asked once per
+    /// run
+    ///
+    /// The helper is run by the shell with the source's url on stdin and prints the token to
+    /// stdout. Printing nothing means the source needs no credentials. It may take a while,
+    /// e.g. to sign in interactively, so it runs on the blocking pool.
+    async fn helper_token(&self) -> Result<Option<String>, Error> {
+        let command = match &self.credential_helper {
+            Some(command) => command,
+            None => return Ok(None),
+        };
+        // Concurrent downloads wait for the first one to ask rather than all asking
+        let mut token = self.helper_token.lock().await;
+        if let Some(token) = &*token {
+            return Ok(token.clone());
+        }
+        let failed = |reason: String| Error::CredentialHelper {
+            url: self.url.clone(),
+            reason,
+        };
+        let (helper, url) = (command.clone(), self.url.clone());
+        let output = tokio::task::spawn_blocking(move || run_credential_helper(&helper, &url))
+            .await
+            .map_err(Error::Task)?
+            .map_err(|e| failed(format!("`{}` could not be run: {}", command, e)))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(failed(format!(
+                "`{}` exited with {}: {}",
+                command,
+                output.status,
+                stderr.trim()
+            )));
+        }
+        let stdout = String::from_utf8(output.stdout)
+            .map_err(|_| failed(format!("`{}` printed a token that isn't UTF-8", command)))?;
+        let value = Some(stdout.trim().to_owned()).filter(|t| !t.is_empty());
+        *token = Some(value.clone());
+        Ok(value)
+    }
+
+    /// Finds the base address packages of this source are downloaded from
+    ///
+    /// The service index of v3 feeds is kept in `cache` for a while, `refresh` always fetches
+    /// it again.
+    pub async fn package_base(
+        &self,
+        client: &Client,
+        cache: Option<&Cache>,
+        refresh: bool,
+    ) -> Result<PackageBase, Error> {
+        let mut base = self.base.lock().await;
+        if let Some(base) = &*base {
+            return Ok(base.clone());
+        }
+        let client = self.client(client);
+        let resolved = self.resolve_package_base(client, cache, refresh).await?;
+        *base = Some(resolved.clone());
+        Ok(resolved)
+    }
+
+    async fn resolve_package_base(
+        &self,
+        client: &Client,
+        cache: Option<&Cache>,
+        refresh: bool,
+    ) -> Result<PackageBase, Error> {
+        if let Some(dir) = self.local_dir() {
+            if !dir.is_dir() {
+                return Err(Error::MissingLocalSource(dir));
+            }
+            return Ok(PackageBase::Local(dir));
+        }
+        if !self.is_v3() {
+            return Ok(PackageBase::V2(self.url.trim_end_matches('/').to_owned()));
+        }
+
+        let overridden = |kind: &str| {
+            self.resources
+                .get(kind)
+                .map(|url| url.trim_end_matches('/').to_owned())
+        };
+        if let (Some(packages), Some(registrations)) = (
+            overridden("PackageBaseAddress"),
+            overridden("RegistrationsBaseUrl"),
+        ) {
+            // Nothing is left to look up in the service index
+            return Ok(PackageBase::V3 {
+                packages,
+                registrations: Some(registrations),
+                search: overridden("SearchQueryService"),
+            });
+        }
+        let cached = cache
+            .filter(|_| !refresh)
+            .and_then(|c| c.get_index(&self.url));
+        let bytes = match cached {
+            Some(bytes) => bytes,
+            None => {
+                let bytes = self.fetch_service_index(client).await?;
+                if let Some(cache) = cache {
+                    if let Err(e) = cache.put_index(&self.url, &bytes) {
+                        warn(format!(
+                            "Could not cache the service index {}: {}",
+                            self.url, e
+                        ));
+                    }
+                }
+                bytes
+            }
+        };
+        let index: ServiceIndex = serde_json::from_slice(&bytes)
+            .map_err(|e| Error::MalformedFeed(self.url.clone(), e.to_string()))?;
+        let find = |kind: &str| overridden(kind).or_else(|| index.find(kind));
+        let packages = find("PackageBaseAddress").ok_or_else(|| {
+            Error::MalformedFeed(
+                self.url.clone(),
+                "its service index has no PackageBaseAddress, which can be given in the source's `resources`".to_owned(),
+            )
+        })?;
+        let registrations = find("RegistrationsBaseUrl");
+        Ok(PackageBase::V3 {
+            packages,
+            registrations,
+            search: find("SearchQueryService"),
+        })
+    }
+
+    async fn fetch_service_index(&self, client: &Client) -> Result<Vec<u8>, Error> {
+        let res = self
+            .authorize(&self.url, client.get(&self.url))
+            .await?
+            .send()
+            .await
+            .map_err(network_error(&self.url))?;
+        if !res.status().is_success() {
+            return Err(Error::HttpStatus {
+                url: self.url.clone(),
+                status: res.status().as_u16(),
+            });
+        }
+        let bytes = res
+            .bytes()
+            .await
+            .map_err(|e| Error::Network(self.url.clone(), e))?;
+        Ok(bytes.to_vec())
+    }
+}
+
+impl std::fmt::Debug for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Never print credentials
+        f.debug_struct("Source")
+            .field("url", &self.url)
+            .field("api_key", &self.api_key.as_ref().map(|_| "<redacted>"))
+            .field("headers", &self.headers)
+            .finish()
+    }
+}
+
+/// The GitHub token to use as the api key if `url` is a GitHub Packages feed
+fn github_api_key(url: &str, token: impl FnOnce() -> Option<String>) -> Option<String> {
+    let host = Url::parse(url).ok()?.host_str()?.to_owned();
+    if host == GITHUB_PACKAGES_HOST {
+        token()
+    } else {
+        None
+    }
+}
+
+/// Runs a credential helper by the shell, like git does, writing `url` to its stdin
+fn run_credential_helper(command: &str, url: &str) -> std::io::Result<std::process::Output> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.args(&["/C", command]);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.args(&["-c", command]);
+        shell
+    };
+    let mut child = shell
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Helpers that don't read stdin may have exited already
+    let _ = writeln!(child.stdin.take().unwrap(), "{}", url);
+    child.wait_with_output()
+}
+
+/// Reads the environment variable `NAME` for values of the form `env:NAME`
+///
+/// Any other value is returned as is.
+fn resolve_env(value: String) -> Result<String, Error> {
+    match value.strip_prefix("env:") {
+        Some(name) => std::env::var(name).map_err(|_| Error::MissingEnvVar(name.to_owned())),
+        None => Ok(value),
+    }
+}
+
+/// Whether requests to `host` skip the proxy according to `no_proxy`, a comma separated
+/// list of hosts and domains like the `NO_PROXY` environment variable
+///
+/// `example.com` and `.example.com` both match example.com and its subdomains, and `*`
+/// matches every host.
+fn bypasses_proxy(no_proxy: &str, host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    no_proxy
+        .split(',')
+        .map(|entry| entry.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| entry == "*" || host == entry || host.ends_with(&format!(".{}", entry)))
+}
+
+/// Replaces every `${NAME}` in a url with the environment variable `NAME`
+fn interpolate_env(url: &str) -> Result<String, Error> {
+    let mut interpolated = String::new();
+    let mut rest = url;
+    while let Some(start) = rest.find("${") {
+        interpolated.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or_else(|| {
+            Error::MalformedManifest(format!("the url {} has an unterminated `${{`", url))
+        })?;
+        let name = &rest[start + 2..start + end];
+        let value = std::env::var(name).map_err(|_| Error::MissingEnvVar(name.to_owned()))?;
+        interpolated.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    interpolated.push_str(rest);
+    Ok(interpolated)
+}
+
+/// Parses a header given as `Name: Value`
+pub fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), String> {
+    match header.split_once(':') {
+        Some((name, value)) => parse_header_parts(name, value),
+        None => Err(format!("expected `Name: Value` but got `{}`", header)),
+    }
+}
+
+fn parse_header_parts(name: &str, value: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("`{}` is not a valid header name", name.trim()))?;
+    let mut value = HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("the value of the `{}` header is invalid", name))?;
+    // Sensitive values are hidden whenever headers are printed
+    let lowercase = name.as_str();
+    if ["auth", "token", "key", "secret", "password", "cookie"]
+        .iter()
+        .any(|s| lowercase.contains(s))
+    {
+        value.set_sensitive(true);
+    }
+    Ok((name, value))
+}
+
+/// The resolved location packages of a source live at
+#[derive(Debug, Clone)]
+pub enum PackageBase {
+    /// A v2 feed which serves packages from `<base>/package/<id>/<version>` and symbol
+    /// packages from `<base>/symbolpackage/<id>/<version>`
+    V2(String),
+    /// A v3 feed's `PackageBaseAddress` resource and its `RegistrationsBaseUrl` and
+    /// `SearchQueryService` resources, if it has them
+    V3 {
+        packages: String,
+        registrations: Option<String>,
+        search: Option<String>,
+    },
+    /// A directory of `<id>.<version>.nupkg` files, directly in it or in any folder below
+    Local(PathBuf),
+}
+
+/// A version listed by a feed
+#[derive(Debug)]
+pub struct ListedVersion {
+    pub version: String,
+    /// When the version was published, only read when asked for
+    pub published: Option<String>,
+}
+
+impl PackageBase {
+    pub fn package_url(&self, dep: &Dependency, kind: PackageKind) -> String {
+        match self {
+            PackageBase::V2(base) => {
+                let endpoint = match kind {
+                    PackageKind::Package => "package",
+                    PackageKind::Symbols => "symbolpackage",
+                };
+                format!("{}/{}/{}/{}", base, endpoint, dep.name, dep.version)
+            }
+            PackageBase::V3 { packages, .. } => {
+                let id = dep.name.to_lowercase();
+                let version = dep.version.to_lowercase();
+                let extension = match kind {
+                    PackageKind::Package => "nupkg",
+                    PackageKind::Symbols => "snupkg",
+                };
+                format!(
+                    "{}/{}/{}/{}.{}.{}",
+                    packages, id, version, id, version, extension
+                )
+            }
+            // Where the package would be in a flat local feed, `find_local` looks for it
+            // in subfolders too
+            PackageBase::Local(dir) => dir
+                .join(local_file_name(&dep.name, &dep.version, kind))
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+
+    /// Finds the given kind of package in a local feed
+    pub fn find_local(&self, dep: &Dependency, kind: PackageKind) -> Option<PathBuf> {
+        let dir = match self {
+            PackageBase::Local(dir) => dir,
+            _ => return None,
+        };
+        let wanted = local_file_name(&dep.name, &dep.version, kind);
+        local_packages(dir).into_iter().find(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.eq_ignore_ascii_case(&wanted))
+        })
+    }
+
+    /// The versions of a package in a local feed
+    pub fn local_versions(&self, name: &str) -> Vec<String> {
+        let dir = match self {
+            PackageBase::Local(dir) => dir,
+            _ => return Vec::new(),
+        };
+        let prefix = format!("{}.", name.to_lowercase());
+        local_packages(dir)
+            .iter()
+            .filter_map(|p| p.file_name()?.to_str().map(str::to_lowercase))
+            .filter_map(|n| {
+                let version = n.strip_suffix(".nupkg")?.strip_prefix(prefix.as_str())?;
+                // `Foo.Bar.Baz.1.0.0` isn't a version of `Foo.Bar`
+                Some(version.to_owned()).filter(|v| v.starts_with(|c: char| c.is_ascii_digit()))
+            })
+            .collect()
+    }
+
+    /// Where the published versions of a package are listed
+    ///
+    /// With `dated` the versions are listed along with when they were published, which v3
+    /// feeds only do in their registrations.
+    pub fn versions_url(&self, name: &str, dated: bool) -> Result<String, Error> {
+        match self {
+            PackageBase::V2(base) => Ok(format!("{}/FindPackagesById()?id='{}'", base, name)),
+            PackageBase::V3 { packages, .. } if !dated => {
+                Ok(format!("{}/{}/index.json", packages, name.to_lowercase()))
+            }
+            PackageBase::V3 {
+                registrations: Some(registrations),
+                ..
+            } => Ok(format!(
+                "{}/{}/index.json",
+                registrations,
+                name.to_lowercase()
+            )),
+            PackageBase::Local(_) => unreachable!("local feeds are listed by `local_versions`"),
+            PackageBase::V3 {
+                registrations: None,
+                packages,
+                ..
+            } => Err(Error::UndatedSource(packages.clone())),
+        }
+    }
+
+    /// Reads the versions out of the page at `url`, as returned by `versions_url`, along with the urls
+    /// of the pages still to be downloaded
+    ///
+    /// v2 feeds answer with an Atom feed which may be split into pages. v3 feeds list every
+    /// version at once, except for registrations which may link to pages instead of listing
+    /// their versions inline.
+    pub fn parse_versions(
+        &self,
+        url: &str,
+        page: &[u8],
+        dated: bool,
+    ) -> Result<(Vec<ListedVersion>, Vec<String>), Error> {
+        let malformed = || {
+            Error::MalformedFeed(
+                url.to_owned(),
+                "its list of versions is malformed".to_owned(),
+            )
+        };
+        match self {
+            PackageBase::V2(_) => {
+                let xml = std::str::from_utf8(page).map_err(|_| malformed())?;
+                let doc = roxmltree::Document::parse(xml).map_err(|_| malformed())?;
+                let property = |entry: roxmltree::Node, name: &str| {
+                    entry
+                        .descendants()
+                        .find(|n| n.is_element() && n.tag_name().name() == name)
+                        .and_then(|n| n.text())
+                        .map(|v| v.trim().to_owned())
+                };
+                let versions = doc
+                    .descendants()
+                    .filter(|n| n.is_element() && n.tag_name().name() == "entry")
+                    .filter_map(|entry| {
+                        Some(ListedVersion {
+                            version: property(entry, "Version")?,
+                            published: property(entry, "Published"),
+                        })
+                    })
+                    .collect();
+                let next = doc
+                    .root_element()
+                    .children()
+                    .find(|n| {
+                        n.is_element()
+                            && n.tag_name().name() == "link"
+                            && n.attribute("rel") == Some("next")
+                    })
+                    .and_then(|n| n.attribute("href"))
+                    .map(str::to_owned);
+                Ok((versions, next.into_iter().collect()))
+            }
+            PackageBase::V3 { .. } if !dated => {
+                let index: VersionIndex = serde_json::from_slice(page).map_err(|_| malformed())?;
+                let versions = index
+                    .versions
+                    .into_iter()
+                    .map(|version| ListedVersion {
+                        version,
+                        published: None,
+                    })
+                    .collect();
+                Ok((versions, Vec::new()))
+            }
+            PackageBase::V3 { .. } => {
+                // Registrations are usually served compressed whether or not that was asked for
+                let page = if page.starts_with(&[0x1f, 0x8b]) {
+                    let mut decoded = Vec::new();
+                    std::io::Read::read_to_end(
+                        &mut flate2::read::GzDecoder::new(page),
+                        &mut decoded,
+                    )
+                    .map_err(|_| malformed())?;
+                    decoded
+                } else {
+                    page.to_vec()
+                };
+                let page: RegistrationPage =
+                    serde_json::from_slice(&page).map_err(|_| malformed())?;
+                let mut versions = Vec::new();
+                let mut pages = Vec::new();
+                page.collect(&mut versions, &mut pages);
+                Ok((versions, pages))
+            }
+            PackageBase::Local(_) => unreachable!("local feeds are listed by `local_versions`"),
+        }
+    }
+}
+
+impl PackageBase {
+    /// Where packages whose id is like `name` are searched for, if the feed can be searched
+    ///
+    /// Local feeds list their ids with `local_ids` instead.
+    pub fn search_url(&self, name: &str) -> Option<String> {
+        let url = match self {
+            PackageBase::V2(base) => Url::parse_with_params(
+                &format!("{}/Search()", base),
+                &[
+                    ("searchTerm", format!("'{}'", name).as_str()),
+                    ("includePrerelease", "true"),
+                ],
+            ),
+            PackageBase::V3 {
+                search: Some(search),
+                ..
+            } => Url::parse_with_params(
+                search,
+                &[
+                    ("q", name),
+                    ("prerelease", "true"),
+                    ("semVerLevel", "2.0.0"),
+                ],
+            ),
+            PackageBase::V3 { search: None, .. } | PackageBase::Local(_) => return None,
+        };
+        url.ok().map(String::from)
+    }
+
+    /// Reads the package ids out of the response to `search_url`, ignoring malformed ones
+    pub fn parse_search(&self, response: &[u8]) -> Vec<String> {
+        match self {
+            PackageBase::V2(_) => {
+                let doc = match std::str::from_utf8(response)
+                    .ok()
+                    .and_then(|xml| roxmltree::Document::parse(xml).ok())
+                {
+                    Some(doc) => doc,
+                    None => return Vec::new(),
+                };
+                doc.descendants()
+                    .filter(|n| n.is_element() && n.tag_name().name() == "entry")
+                    .filter_map(|entry| {
+                        entry
+                            .descendants()
+                            .find(|n| n.is_element() && n.tag_name().name() == "Id")
+                            .and_then(|n| n.text())
+                            .map(|id| id.trim().to_owned())
+                    })
+                    .collect()
+            }
+            PackageBase::V3 { .. } => serde_json::from_slice::<SearchResults>(response)
+                .map(|r| r.data.into_iter().map(|p| p.id).collect())
+                .unwrap_or_default(),
+            PackageBase::Local(_) => Vec::new(),
+        }
+    }
+
+    /// The ids of the packages in a local feed, as their file names spell them
+    pub fn local_ids(&self) -> Vec<String> {
+        let dir = match self {
+            PackageBase::Local(dir) => dir,
+            _ => return Vec::new(),
+        };
+        let mut ids: Vec<String> = local_packages(dir)
+            .iter()
+            .filter_map(|p| p.file_stem()?.to_str().map(str::to_owned))
+            .filter_map(|stem| {
+                // The version starts with the first part that's a number
+                let parts: Vec<&str> = stem.split('.').collect();
+                let version = parts
+                    .iter()
+                    .position(|p| p.starts_with(|c: char| c.is_ascii_digit()))?;
+                Some(parts[..version].join(".")).filter(|id| !id.is_empty())
+            })
+            .collect();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+}
+
+/// The name of a package's file in a local feed, which is matched case insensitively
+fn local_file_name(name: &str, version: &str, kind: PackageKind) -> String {
+    let extension = match kind {
+        PackageKind::Package => "nupkg",
+        PackageKind::Symbols => "snupkg",
+    };
+    format!("{}.{}.{}", name, version, extension)
+}
+
+/// Every package and symbol package file in a local feed
+fn local_packages(dir: &Path) -> Vec<PathBuf> {
+    let mut packages = Vec::new();
+    let mut dirs = vec![dir.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|e| {
+                e.eq_ignore_ascii_case("nupkg") || e.eq_ignore_ascii_case("snupkg")
+            }) {
+                packages.push(path);
+            }
+        }
+    }
+    packages
+}
+
+/// The packages a source can serve for a dependency
+#[derive(Debug, Clone, Copy)]
+pub enum PackageKind {
+    /// The `.nupkg` itself
+    Package,
+    /// The `.snupkg` holding the package's PDBs
+    Symbols,
+}
+
+#[derive(Deserialize)]
+struct ServiceIndex {
+    resources: Vec<Resource>,
+}
+
+impl ServiceIndex {
+    /// The url of the resource of the given type, preferring the versions listed in
+    /// `RESOURCE_TYPES` in order and then any other version
+    fn find(&self, kind: &str) -> Option<String> {
+        let versions = RESOURCE_TYPES
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map_or(&[][..], |(_, v)| v);
+        let exact = versions.iter().find_map(|version| {
+            let wanted = format!("{}/{}", kind, version);
+            self.resources.iter().find(|r| r.kind == wanted)
+        });
+        let any = || {
+            self.resources.iter().find(|r| {
+                r.kind == kind
+                    || r.kind
+                        .strip_prefix(kind)
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+        };
+        exact
+            .or_else(any)
+            .map(|r| r.id.trim_end_matches('/').to_owned())
+    }
+}
+
+#[derive(Deserialize)]
+struct Resource {
+    #[serde(rename = "@id")]
+    id: String,
+    #[serde(rename = "@type")]
+    kind: String,
+}
+
+#[derive(Deserialize)]
+struct SearchResults {
+    data: Vec<SearchResult>,
+}
+
+#[derive(Deserialize)]
+struct SearchResult {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct VersionIndex {
+    versions: Vec<String>,
+}
+
+/// A registration index or one of its pages
+#[derive(Deserialize)]
+struct RegistrationPage {
+    #[serde(default)]
+    items: Vec<RegistrationItem>,
+}
+
+/// Either a page of a registration index or a single version (a leaf) in a page
+#[derive(Deserialize)]
+struct RegistrationItem {
+    #[serde(rename = "@id")]
+    id: String,
+    /// The leaves of a page, left out when the page has to be downloaded separately
+    items: Option<Vec<RegistrationItem>>,
+    #[serde(rename = "catalogEntry")]
+    catalog_entry: Option<CatalogEntry>,
+}
+
+#[derive(Deserialize)]
+struct CatalogEntry {
+    version: String,
+    published: Option<String>,
+}
+
+impl RegistrationPage {
+    fn collect(self, versions: &mut Vec<ListedVersion>, pages: &mut Vec<String>) {
+        for item in self.items {
+            match (item.catalog_entry, item.items) {
+                // Build metadata isn't part of the version packages are downloaded by
+                (Some(entry), _) => versions.push(ListedVersion {
+                    version: entry.version.split('+').next().unwrap().to_owned(),
+                    published: entry.published,
+                }),
+                (None, Some(items)) => RegistrationPage { items }.collect(versions, pages),
+                (None, None) => pages.push(item.id),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use reqwest::header::AUTHORIZATION;
+
+    const GITHUB_FEED: &str = "https://nuget.pkg.github.com/owner/index.json";
+
+    /// The Authorization header the source adds to a request to `url`
+    fn authorization(source: &Source, url: &str) -> Option<String> {
+        let request = futures::executor::block_on(source.authorize(url, Client::new().get(url)))
+            .unwrap()
+            .build()
+            .unwrap();
+        request
+            .headers()
+            .get(AUTHORIZATION)
+            .map(|v| v.to_str().unwrap().to_owned())
+    }
+
+    #[test]
+    fn github_packages_fall_back_to_github_token() {
+        let token = || Some("ghp_test".to_owned());
+        assert_eq!(
+            github_api_key(GITHUB_FEED, token).as_deref(),
+            Some("ghp_test")
+        );
+        assert_eq!(
+            github_api_key("https://example.com/index.json", token),
+            None
+        );
+        assert_eq!(github_api_key(GITHUB_FEED, || None), None);
+        // A key that's configured wins
+        let configured = Source::new(GITHUB_FEED.to_owned(), Some("configured".to_owned()));
+        assert_eq!(configured.api_key.as_deref(), Some("configured"));
+    }
+
+    #[test]
+    fn basic_auth_is_sent_to_the_same_host() {
+        let source = Source::new(GITHUB_FEED.to_owned(), Some("key".to_owned()));
+        let url = "https://nuget.pkg.github.com/owner/download/test/1.0.0/test.1.0.0.nupkg";
+        let header = authorization(&source, url).unwrap();
+        // `cargo-nuget:key` in base64
+        assert_eq!(header, "Basic Y2FyZ28tbnVnZXQ6a2V5");
+    }
+
+    #[test]
+    fn no_credentials_are_sent_on_cross_host_redirects() {
+        let mut source = Source::new(GITHUB_FEED.to_owned(), Some("key".to_owned()));
+        source.add_header(
+            HeaderName::from_static("x-custom"),
+            HeaderValue::from_static("secret"),
+        );
+        // Where GitHub Packages redirects package downloads to
+        let url = "https://pkg-containers.githubusercontent.com/ghcr1/blobs/sha256:0";
+        assert!(authorization(&source, url).is_none());
+        let request = futures::executor::block_on(source.authorize(url, Client::new().get(url)))
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(request.headers().get("x-custom").is_none());
+    }
+
+    fn configured(url: &str) -> Source {
+        let config: SourceConfig = toml::from_str(&format!("url = {:?}", url)).unwrap();
+        Source::from_config(config).unwrap()
+    }
+
+    #[test]
+    fn env_urls_are_recorded_as_configured() {
+        std::env::set_var(
+            "CARGO_NUGET_TEST_FEED_URL",
+            "https://feed.example.com/index.json",
+        );
+        let source = configured("env:CARGO_NUGET_TEST_FEED_URL");
+        assert_eq!(source.url(), "https://feed.example.com/index.json");
+        assert_eq!(source.recorded_url(), "env:CARGO_NUGET_TEST_FEED_URL");
+        assert!(source.is("env:CARGO_NUGET_TEST_FEED_URL"));
+        // Lock files written before the configured url was recorded still match
+        assert!(source.is("https://feed.example.com/index.json/"));
+    }
+
+    #[test]
+    fn interpolated_urls_are_recorded_as_configured() {
+        std::env::set_var("CARGO_NUGET_TEST_ORG", "contoso");
+        let url = "https://pkgs.dev.azure.com/${CARGO_NUGET_TEST_ORG}/nuget/v3/index.json";
+        let source = configured(url);
+        assert_eq!(
+            source.url(),
+            "https://pkgs.dev.azure.com/contoso/nuget/v3/index.json"
+        );
+        assert_eq!(source.recorded_url(), url);
+        assert!(source.is(url));
+    }
+}
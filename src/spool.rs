@@ -0,0 +1,78 @@
+use crate::{read_error, write_error, Error};
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Distinguishes the spool files of downloads running at the same time
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A temporary file a download is streamed into as it arrives
+///
+/// The file is removed once the spool is dropped, including when the download fails or is
+/// cancelled part way through.
+#[derive(Debug)]
+pub struct Spool {
+    path: PathBuf,
+    /// Only `None` while the spool is dropped, as Windows can't remove open files
+    file: Option<File>,
+    written: usize,
+}
+
+impl Spool {
+    pub fn new(dir: &Path) -> Result<Self, Error> {
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        let path = dir.join(format!("cargo-nuget-{}-{}.part", std::process::id(), id));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(write_error(&path))?;
+        Ok(Self {
+            path,
+            file: Some(file),
+            written: 0,
+        })
+    }
+
+    /// Checks up front that spool files can be created in `dir`
+    pub fn check_dir(dir: &Path) -> Result<(), Error> {
+        match Self::new(dir) {
+            Ok(_) => Ok(()),
+            Err(Error::WriteFailed(_, e)) => Err(Error::TempDirNotWritable(dir.to_owned(), e)),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn write(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        let file = self.file.as_mut().unwrap();
+        file.write_all(chunk).map_err(write_error(&self.path))?;
+        self.written += chunk.len();
+        Ok(())
+    }
+
+    /// The number of bytes written so far
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    /// Reads back everything that was written
+    pub fn finish(self) -> Result<Vec<u8>, Error> {
+        let mut file = self.file.as_ref().unwrap();
+        file.seek(SeekFrom::Start(0))
+            .map_err(read_error(&self.path))?;
+        let mut bytes = Vec::with_capacity(self.written);
+        file.read_to_end(&mut bytes)
+            .map_err(read_error(&self.path))?;
+        Ok(bytes)
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        self.file.take();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::{write_error, Error};
+
+use sha2::{Digest, Sha256};
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+/// A directory holding every extracted file once, named by the hash of its contents
+///
+/// Installs using a store only contain links into it so projects sharing package versions
+/// share the files on disk too.
+#[derive(Debug, Clone)]
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    pub fn new(dir: PathBuf) -> Result<Self, Error> {
+        std::fs::create_dir_all(&dir).map_err(write_error(&dir))?;
+        // Links are written into a temporary directory before being moved into place so
+        // they have to point at an absolute path
+        let dir = dir.canonicalize().map_err(write_error(&dir))?;
+        Ok(Self { dir })
+    }
+
+    /// Where a file with the given contents is kept
+    fn path(&self, contents: &[u8]) -> PathBuf {
+        let hash = format!("{:x}", Sha256::digest(contents));
+        self.dir.join(&hash[..2]).join(hash)
+    }
+
+    /// Adds a file to the store unless it's already there and returns its path
+    fn insert(&self, contents: &[u8], modified: Option<SystemTime>) -> Result<PathBuf, Error> {
+        static INSERTS: AtomicUsize = AtomicUsize::new(0);
+        let path = self.path(contents);
+        if path.exists() {
+            return Ok(path);
+        }
+        std::fs::create_dir_all(path.parent().unwrap()).map_err(write_error(&path))?;
+        // Write under a temporary name first so an interrupted install can't leave a
+        // truncated file behind that later installs would link to. Installs sharing the
+        // store may be adding the same file at the same time so each gets a name of its own.
+        let tmp = path.with_extension(format!(
+            "{}.{}.tmp",
+            std::process::id(),
+            INSERTS.fetch_add(1, Ordering::Relaxed)
+        ));
+        if let Err(e) = crate::write_file(&tmp, contents, modified, None)
+            .and_then(|_| std::fs::rename(&tmp, &path).map_err(write_error(&path)))
+        {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(e);
+        }
+        Ok(path)
+    }
+
+    /// Makes `path` refer to the stored copy of `contents`
+    ///
+    /// A symlink is used where possible, falling back to a hard link and finally a plain
+    /// copy if the platform or filesystem supports neither.
+    pub fn link(
+        &self,
+        path: &Path,
+        contents: &[u8],
+        modified: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        let stored = self.insert(contents, modified)?;
+        if symlink(&stored, path).is_ok() || std::fs::hard_link(&stored, path).is_ok() {
+            return Ok(());
+        }
+        crate::write_file(path, contents, modified, None)
+    }
+}
+
+/// Symlinks to files need to be told apart from symlinks to directories on Windows
+#[cfg(windows)]
+fn symlink(original: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(original, link)
+}
+
+#[cfg(unix)]
+fn symlink(original: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
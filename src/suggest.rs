@@ -0,0 +1,46 @@
+/// How many suggestions are offered at most
+const MAX_SUGGESTIONS: usize = 3;
+
+/// The candidates that look like a mistyped `name`, closest first
+///
+/// Package ids are compared case insensitively, and a candidate is close enough if a few
+/// characters were left out, added or swapped, more for longer ids.
+pub fn closest(name: &str, candidates: &[String]) -> Vec<String> {
+    let name = name.to_lowercase();
+    let threshold = std::cmp::max(2, name.chars().count() / 4);
+    let mut close: Vec<(usize, &String)> = candidates
+        .iter()
+        .filter_map(|c| {
+            let distance = edit_distance(&name, &c.to_lowercase());
+            // An id that differs only in case isn't a typo, the package would have been found
+            Some((distance, c)).filter(|_| distance > 0 && distance <= threshold)
+        })
+        .collect();
+    close.sort();
+    let mut suggestions: Vec<String> = Vec::new();
+    for (_, candidate) in close {
+        if !suggestions
+            .iter()
+            .any(|s| s.eq_ignore_ascii_case(candidate))
+        {
+            suggestions.push(candidate.clone());
+        }
+    }
+    suggestions.truncate(MAX_SUGGESTIONS);
+    suggestions
+}
+
+/// The Levenshtein distance between two strings, counted in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
@@ -0,0 +1,38 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Limits the combined throughput of all downloads of an install
+///
+/// Every download waits after reading a chunk until the chunk fits into the rate, which
+/// keeps the server from sending faster than that.
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_sec: u64,
+    /// When all bytes read so far are allowed to have arrived
+    next: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until the given number of bytes can be received without exceeding the rate
+    pub async fn consume(&self, bytes: usize) {
+        let until = {
+            let mut next = self.next.lock().unwrap();
+            // Time nothing was downloaded in doesn't allow bursts later on
+            let now = Instant::now();
+            if *next < now {
+                *next = now;
+            }
+            *next += Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+            *next
+        };
+        tokio::time::delay_until(until).await;
+    }
+}
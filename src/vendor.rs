@@ -0,0 +1,94 @@
+use crate::lock::Lockfile;
+use crate::report::FileReport;
+use crate::{read_error, write_error, Error};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::path::Path;
+
+/// The name of the manifest written into a vendor directory
+pub const VENDOR_FILE_NAME: &str = "nuget-vendor.toml";
+
+const HEADER: &str = "# This file is automatically generated by cargo nuget --vendor.\n\
+                      # It is not intended for manual editing.\n";
+
+/// A record of the packages extracted into a vendor directory and their files
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct VendorManifest {
+    #[serde(default, rename = "package")]
+    pub packages: Vec<VendoredPackage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VendoredPackage {
+    pub name: String,
+    pub version: String,
+    /// The SHA-256 hash of the nupkg the files were extracted from
+    pub sha256: String,
+    /// Every file extracted into the package's directory
+    #[serde(default, rename = "file")]
+    pub files: Vec<FileReport>,
+}
+
+impl VendorManifest {
+    /// Reads the manifest of the vendor directory `dir`, returning `None` if there isn't one
+    pub fn load(dir: &Path) -> Result<Option<Self>, Error> {
+        let path = dir.join(VENDOR_FILE_NAME);
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::ReadFailed(path, e)),
+        };
+        toml::from_str(&contents)
+            .map(Some)
+            .map_err(|_| Error::MalformedVendorManifest(path))
+    }
+
+    pub fn save(&mut self, dir: &Path) -> Result<(), Error> {
+        let path = dir.join(VENDOR_FILE_NAME);
+        self.packages.sort_by(|a, b| a.name.cmp(&b.name));
+        let contents = toml::to_string(self).map_err(|e| Error::Serialize("vendor manifest", e))?;
+        std::fs::write(&path, format!("{}{}", HEADER, contents)).map_err(write_error(&path))
+    }
+
+    /// Records newly vendored packages, keeping entries for packages that are still
+    /// declared but weren't installed this time, like `Lockfile::update`
+    pub fn update(&mut self, vendored: Vec<VendoredPackage>, declared: &[String]) {
+        self.packages
+            .retain(|p| declared.contains(&p.name) && !vendored.iter().any(|v| v.name == p.name));
+        self.packages.extend(vendored);
+    }
+
+    /// Checks that the package `name` is vendored in `dir` exactly as the lock file recorded
+    /// it and that none of its files were changed since
+    pub fn verify(&self, dir: &Path, name: &str, lockfile: &Lockfile) -> Result<(), Error> {
+        let vendored = self
+            .packages
+            .iter()
+            .find(|p| p.name == name)
+            .ok_or_else(|| Error::NotVendored(name.to_owned()))?;
+        let locked = lockfile
+            .find(name, &vendored.version)
+            .ok_or_else(|| Error::VendorOutdated(name.to_owned(), vendored.version.clone()))?;
+        if locked
+            .sha256
+            .as_ref()
+            .is_some_and(|h| *h != vendored.sha256)
+        {
+            return Err(Error::VendorOutdated(
+                name.to_owned(),
+                vendored.version.clone(),
+            ));
+        }
+        let package_dir = dir.join(name);
+        for file in &vendored.files {
+            let path = package_dir.join(&file.path);
+            let contents = std::fs::read(&path).map_err(read_error(&path))?;
+            if format!("{:x}", Sha256::digest(&contents)) != file.sha256 {
+                return Err(Error::VendoredFileChanged(path));
+            }
+        }
+        Ok(())
+    }
+}